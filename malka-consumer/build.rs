@@ -0,0 +1,11 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Relies on the vendored protoc, unless one is explicitly given.
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+
+    tonic_build::configure()
+        .build_server(false)
+        .compile(&["proto/malka.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use log::error;
+use rusoto_core::{Client, Region};
+use rusoto_core::param::{Params, ServiceParams};
+use rusoto_core::signature::SignedRequest;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::aws::query::encode_form;
+use crate::conf::{SnsConfig, SqsConfig};
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError, RecordCoordinates};
+use crate::kafka::payload::PayloadEncoder;
+
+/// Max number of messages sent by a single `SendMessageBatch` or `PublishBatch` request.
+const MAX_BATCH_ENTRIES: usize = 10;
+/// Max size, in bytes, of all messages sent by a single batch request.
+const MAX_BATCH_SIZE: usize = 256 * 1024;
+
+const SQS_API_VERSION: &str = "2012-11-05";
+const SNS_API_VERSION: &str = "2010-03-31";
+
+/// Where messages are sent to.
+pub enum BatchDestination {
+    Sqs(SqsConfig),
+    Sns(SnsConfig)
+}
+
+/// A `KafkaConsumerListener` implementation that sends every record as a message
+/// to an SQS queue (`SendMessageBatch`) or an SNS topic (`PublishBatch`). Records
+/// are split into as many batch requests as their limits require. Records are only
+/// considered consumed once every message was accepted. Rejected messages only
+/// affect their own records, but retrying them will send again the messages that were accepted.
+pub struct BatchKafkaConsumerListener {
+    destination: BatchDestination,
+    payload_encoder: PayloadEncoder,
+    client: Client,
+    region: Region
+}
+
+/// A record, along with the message it is sent as.
+struct Entry<'a> {
+    record: &'a InFlightRecord,
+    message: String
+}
+
+/// A message that was rejected by the destination.
+#[derive(Debug, PartialEq)]
+struct FailedEntry {
+    id: String,
+    code: String,
+    sender_fault: bool,
+    message: String
+}
+
+impl BatchKafkaConsumerListener {
+    pub fn create(destination: BatchDestination, payload_encoder: PayloadEncoder, client: Client, region: Region) -> Self {
+        BatchKafkaConsumerListener {
+            destination, client, region,
+            payload_encoder: payload_encoder.unwrapping_single_records()
+        }
+    }
+
+    fn destination_name(&self) -> &str {
+        match &self.destination {
+            BatchDestination::Sqs(config) => &config.queue_url,
+            BatchDestination::Sns(config) => &config.topic_arn
+        }
+    }
+
+    fn create_request(&self, entries: &[Entry]) -> SignedRequest {
+        let mut params = Params::new();
+        let (service, entry_prefix, message_field, message_group_id) = match &self.destination {
+            BatchDestination::Sqs(config) => {
+                params.put("Action", "SendMessageBatch");
+                params.put("Version", SQS_API_VERSION);
+                params.put("QueueUrl", &config.queue_url);
+                ("sqs", "SendMessageBatchRequestEntry", "MessageBody", &config.message_group_id)
+            },
+            BatchDestination::Sns(config) => {
+                params.put("Action", "PublishBatch");
+                params.put("Version", SNS_API_VERSION);
+                params.put("TopicArn", &config.topic_arn);
+                ("sns", "PublishBatchRequestEntries.member", "Message", &config.message_group_id)
+            }
+        };
+
+        for (index, entry) in entries.iter().enumerate() {
+            let prefix = format!("{}.{}", entry_prefix, index + 1);
+            params.put(&format!("{}.Id", prefix), index.to_string());
+            params.put(&format!("{}.{}", prefix, message_field), &entry.message);
+            if let Some(message_group_id) = message_group_id {
+                let record = entry.record;
+                params.put(&format!("{}.MessageGroupId", prefix), message_group_id);
+                params.put(&format!("{}.MessageDeduplicationId", prefix),
+                           format!("{}-{}-{}", &record.topic, record.partition, record.offset));
+            }
+        }
+
+        let mut request = SignedRequest::new("POST", service, &self.region, "/");
+        request.set_content_type("application/x-www-form-urlencoded".to_string());
+        request.set_payload(Some(encode_form(&params)));
+        request
+    }
+
+    /// Sends a single batch request, returning why its messages were rejected (if they were).
+    /// Failures only affect the records of the rejected messages.
+    async fn send_batch(&self, entries: &[Entry<'_>]) -> Vec<KafkaConsumerError> {
+        let destination = self.destination_name();
+        let batch_records = || entries.iter().map(|entry| entry.record.coordinates()).collect::<Vec<RecordCoordinates>>();
+        let response = match self.client.sign_and_dispatch(self.create_request(entries)).await {
+            Ok(mut response) => response.buffer().await
+                .map_err(|cause| FailureCause::with_source(format!("Failed to reach {}", destination), cause)),
+            Err(cause) => Err(FailureCause::new(format!("Failed to reach {}: {:?}", destination, cause)))
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(cause) => return vec!(KafkaConsumerError::Network(cause).affecting(batch_records()))
+        };
+
+        let body = response.body_as_str();
+        if !response.status.is_success() {
+            error!("{} rejected {} message(s): {}", destination, entries.len(), body);
+            let code = parse_error_code(body).unwrap_or_default();
+            let cause = FailureCause::new(format!("{} responded with {}: {}", destination, response.status, code));
+            return vec!(classify(&code, response.status.is_client_error())(cause).affecting(batch_records()))
+        }
+
+        let (scope, entry_element) = match self.destination {
+            BatchDestination::Sqs(_) => ("SendMessageBatchResult", "BatchResultErrorEntry"),
+            BatchDestination::Sns(_) => ("Failed", "member")
+        };
+        match parse_failed_entries(body, scope, entry_element) {
+            Ok(failed_entries) => failed_entries.into_iter()
+                .map(|failed| {
+                    let entry = failed.id.parse::<usize>().ok().and_then(|index| entries.get(index));
+                    let record = entry
+                        .map(|entry| format!("{}-{}@{}", &entry.record.topic, entry.record.partition, entry.record.offset))
+                        .unwrap_or_else(|| failed.id.clone());
+                    let cause = FailureCause::new(format!(
+                        "{} rejected record {}: {} ({})", destination, record, &failed.code, &failed.message));
+                    // Unknown ids can't be told apart, so they affect the whole batch request.
+                    let affected = entry.map_or_else(batch_records, |entry| vec!(entry.record.coordinates()));
+                    classify(&failed.code, failed.sender_fault)(cause).affecting(affected)
+                })
+                .collect(),
+            Err(cause) => vec!(KafkaConsumerError::Network(FailureCause::with_source(
+                format!("Unexpected response from {}", destination), cause)).affecting(batch_records()))
+        }
+    }
+}
+
+/// Splits entries into batches respecting both the number of messages and size limits.
+fn split_into_batches(entries: Vec<Entry>) -> Vec<Vec<Entry>> {
+    let mut batches: Vec<Vec<Entry>> = Vec::new();
+    let mut batch_size = 0;
+    for entry in entries {
+        let is_full = match batches.last() {
+            Some(batch) => batch.len() >= MAX_BATCH_ENTRIES || batch_size + entry.message.len() > MAX_BATCH_SIZE,
+            None => true
+        };
+        if is_full {
+            batches.push(Vec::with_capacity(MAX_BATCH_ENTRIES));
+            batch_size = 0;
+        }
+        batch_size += entry.message.len();
+        batches.last_mut().unwrap().push(entry);
+    }
+    batches
+}
+
+/// Decides how a failure should be handled, based on the AWS error code.
+fn classify(code: &str, sender_fault: bool) -> fn(FailureCause) -> KafkaConsumerError {
+    if code.contains("Throttl") {
+        KafkaConsumerError::Throttled
+    } else if code.contains("TooLong") || code.contains("TooLarge") {
+        KafkaConsumerError::PayloadTooLarge
+    } else if code.contains("NonExistentQueue") || code.contains("QueueDoesNotExist") || code.contains("NotFound") {
+        KafkaConsumerError::NotFound
+    } else if code.contains("AccessDenied") || code.contains("AuthorizationError") || code.contains("InvalidClientTokenId") {
+        KafkaConsumerError::PermissionDenied
+    } else if sender_fault {
+        KafkaConsumerError::InvalidPayload
+    } else {
+        KafkaConsumerError::Network
+    }
+}
+
+/// Reads the `Code` out of an AWS `ErrorResponse` document.
+fn parse_error_code(body: &str) -> Option<String> {
+    let mut is_code = false;
+    for event in EventReader::from_str(body) {
+        match event.ok()? {
+            XmlEvent::StartElement { name, .. } => is_code = name.local_name == "Code",
+            XmlEvent::Characters(value) if is_code => return Some(value),
+            _ => is_code = false
+        }
+    }
+    None
+}
+
+/// Reads the rejected messages out of a batch response. Every `entry_element`
+/// within `scope_element` describes a single rejected message.
+fn parse_failed_entries(body: &str, scope_element: &str, entry_element: &str) -> Result<Vec<FailedEntry>, xml::reader::Error> {
+    let mut failed_entries = Vec::new();
+    let mut in_scope = false;
+    let mut fields: Option<HashMap<String, String>> = None;
+    let mut current_field: Option<String> = None;
+
+    for event in EventReader::from_str(body) {
+        match event? {
+            XmlEvent::StartElement { name, .. } => match name.local_name.as_str() {
+                element if element == scope_element => in_scope = true,
+                element if in_scope && element == entry_element => fields = Some(HashMap::new()),
+                element => current_field = Some(element.to_string())
+            },
+            XmlEvent::Characters(value) => if let (Some(fields), Some(field)) = (&mut fields, &current_field) {
+                fields.insert(field.clone(), value);
+            },
+            XmlEvent::EndElement { name } => {
+                current_field = None;
+                if name.local_name == scope_element {
+                    in_scope = false;
+                } else if in_scope && name.local_name == entry_element {
+                    if let Some(mut fields) = fields.take() {
+                        let mut field = |name: &str| fields.remove(name).unwrap_or_default();
+                        failed_entries.push(FailedEntry {
+                            id: field("Id"),
+                            code: field("Code"),
+                            sender_fault: field("SenderFault") == "true",
+                            message: field("Message")
+                        });
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(failed_entries)
+}
+
+#[async_trait]
+impl KafkaConsumerListener for BatchKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let mut failures = Vec::new();
+        let mut entries = Vec::with_capacity(records.len());
+        for record in records {
+            let message = String::from_utf8_lossy(&self.payload_encoder.encode(std::slice::from_ref(record))).into_owned();
+            if message.len() > MAX_BATCH_SIZE {
+                failures.push(KafkaConsumerError::PayloadTooLarge(FailureCause::new(format!(
+                    "Record {}-{}@{} is too big to be sent to {}",
+                    &record.topic, record.partition, record.offset, self.destination_name())))
+                    .affecting(vec!(record.coordinates())));
+                continue
+            }
+            entries.push(Entry { record, message });
+        }
+
+        let batches = split_into_batches(entries);
+        let mut requests = Vec::with_capacity(batches.len());
+        for batch in &batches {
+            requests.push(self.send_batch(batch));
+        }
+        for batch_failures in futures::future::join_all(requests).await {
+            failures.extend(batch_failures);
+        }
+
+        match KafkaConsumerError::prevailing(failures) {
+            Some(cause) => KafkaConsumerResult::Failed(cause),
+            None => KafkaConsumerResult::Succeeded
+        }
+    }
+
+    /// Records are sent as messages of their own, so bigger ones are handed to the `OversizedRecordHandler`.
+    fn max_record_size(&self) -> Option<usize> {
+        Some(MAX_BATCH_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::{Body, Request, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use rusoto_core::{Client, HttpClient, Region};
+    use rusoto_core::credential::StaticProvider;
+
+    use crate::conf::{PayloadFormat, SnsConfig, SqsConfig};
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+    use crate::kafka::error::KafkaConsumerError;
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::{BatchDestination, BatchKafkaConsumerListener, Entry, FailedEntry, parse_failed_entries, split_into_batches};
+
+    /// Stands in for SQS, answering every request with `response`, keeping track of the received bodies.
+    fn serve(response: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_by_server = Arc::clone(&received);
+        let make_service = make_service_fn(move |_| {
+            let received = Arc::clone(&received_by_server);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let received = Arc::clone(&received);
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        received.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+                        Ok::<_, Infallible>(Response::new(Body::from(response)))
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (endpoint, received)
+    }
+
+    fn record(offset: i64, value: &str) -> InFlightRecord {
+        InFlightRecord { topic: "user.delete".to_string(), offset, ..InFlightRecord::create(None, Some(value.as_bytes())) }
+    }
+
+    #[test]
+    fn should_split_batches_by_number_of_messages_and_size() {
+        let records: Vec<InFlightRecord> = (0..25).map(|offset| record(offset, "v")).collect();
+        let entries = records.iter().map(|record| Entry { record, message: "v".to_string() }).collect();
+        let sizes: Vec<usize> = split_into_batches(entries).iter().map(Vec::len).collect();
+        assert_eq!(vec!(10, 10, 5), sizes);
+
+        let big_message = "v".repeat(100 * 1024);
+        let entries = records.iter().take(5).map(|record| Entry { record, message: big_message.clone() }).collect();
+        let sizes: Vec<usize> = split_into_batches(entries).iter().map(Vec::len).collect();
+        assert_eq!(vec!(2, 2, 1), sizes);
+    }
+
+    #[test]
+    fn should_read_rejected_sns_messages() {
+        let body = r#"<PublishBatchResponse><PublishBatchResult>
+          <Successful><member><Id>0</Id><MessageId>a1b2</MessageId></member></Successful>
+          <Failed><member><Id>1</Id><Code>InternalError</Code><SenderFault>false</SenderFault><Message>Boom</Message></member></Failed>
+        </PublishBatchResult></PublishBatchResponse>"#;
+
+        let expected = FailedEntry {
+            id: "1".to_string(), code: "InternalError".to_string(), sender_fault: false, message: "Boom".to_string()
+        };
+        assert_eq!(vec!(expected), parse_failed_entries(body, "Failed", "member").unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_send_records_to_sqs_and_report_rejected_messages() {
+        let (endpoint, received) = serve(r#"<SendMessageBatchResponse><SendMessageBatchResult>
+          <SendMessageBatchResultEntry><Id>0</Id><MessageId>a1b2</MessageId></SendMessageBatchResultEntry>
+          <BatchResultErrorEntry><Id>1</Id><Code>ThrottlingException</Code><SenderFault>true</SenderFault><Message>Slow down</Message></BatchResultErrorEntry>
+        </SendMessageBatchResult></SendMessageBatchResponse>"#);
+        let destination = BatchDestination::Sqs(SqsConfig {
+            queue_url: format!("{}/000000000000/users", &endpoint),
+            message_group_id: None
+        });
+        let client = Client::new_with(StaticProvider::new_minimal("key".to_string(), "secret".to_string()), HttpClient::new().unwrap());
+        let region = Region::Custom { name: "us-east-1".to_string(), endpoint };
+        let listener = BatchKafkaConsumerListener::create(
+            destination, PayloadEncoder::create(PayloadFormat::Array, String::new()), client, region);
+
+        let result = listener.consume(&[record(0, "first"), record(1, "second")]).await;
+        match result {
+            KafkaConsumerResult::Failed(failure @ KafkaConsumerError::Throttled(_)) =>
+                assert_eq!(Some(&[("user.delete".to_string(), 0, 1)][..]), failure.cause().records()),
+            result => panic!("unexpected result {:?}", result)
+        }
+        assert_eq!(Some(256 * 1024), listener.max_record_size());
+
+        let body = &received.lock().unwrap()[0];
+        assert!(body.contains("Action=SendMessageBatch"));
+        assert!(body.contains("SendMessageBatchRequestEntry.2.MessageBody=%7B%22key%22%3Anull%2C%22value%22%3A%22second%22%7D"));
+    }
+
+    #[test]
+    fn should_publish_to_sns_topics() {
+        let destination = BatchDestination::Sns(SnsConfig {
+            topic_arn: "arn:aws:sns:us-east-1:000000000000:users.fifo".to_string(),
+            message_group_id: Some("users".to_string())
+        });
+        let client = Client::new_with(StaticProvider::new_minimal("key".to_string(), "secret".to_string()), HttpClient::new().unwrap());
+        let listener = BatchKafkaConsumerListener::create(
+            destination, PayloadEncoder::create(PayloadFormat::Array, String::new()), client, Region::UsEast1);
+
+        let record = record(7, "v");
+        let request = listener.create_request(&[Entry { record: &record, message: "v".to_string() }]);
+        let payload = match request.payload {
+            Some(rusoto_core::signature::SignedRequestPayload::Buffer(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => panic!("Expected a buffered payload")
+        };
+        assert!(payload.contains("Action=PublishBatch"));
+        assert!(payload.contains("PublishBatchRequestEntries.member.1.MessageDeduplicationId=user.delete-0-7"));
+    }
+}
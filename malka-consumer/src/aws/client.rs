@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper_tls::HttpsConnector;
+use log::info;
+use rusoto_core::{Client, HttpClient, Region};
+use rusoto_core::credential::{AutoRefreshingProvider, DefaultCredentialsProvider};
+use rusoto_lambda::LambdaClient;
+
+use crate::aws::credentials::AssumeRoleCredentialsProvider;
+use crate::conf::TargetFunction;
+use crate::error::{KnownHandledErrors, Result};
+
+/// Identifies which `LambdaClient` is able to reach a given target function.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ClientKey {
+    /// The function region, along with its custom endpoint (if any).
+    region: Region,
+    /// The role to be assumed, along with the STS region it's assumed through.
+    role: Option<(String, Region)>
+}
+
+impl ClientKey {
+    fn of(target_function: &TargetFunction) -> Result<Self> {
+        let role = match &target_function.role_arn {
+            Some(role_arn) => Some((role_arn.clone(), sts_region_of(target_function)?)),
+            None => None
+        };
+        Ok(ClientKey { region: region_of(target_function)?, role })
+    }
+}
+
+/// How the HTTP connection pool, shared by every `LambdaClient`, is sized.
+/// Read from the `LAMBDA_POOL_MAX_IDLE_PER_HOST` and `LAMBDA_POOL_IDLE_TIMEOUT`
+/// (in milliseconds) environment variables. Unset values fall back to hyper's defaults.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct HttpPoolConfig {
+    pub max_idle_per_host: Option<usize>,
+    pub idle_timeout: Option<Duration>
+}
+
+impl HttpPoolConfig {
+    pub fn from_env() -> Result<Self> {
+        let max_idle_per_host = read_env_var("LAMBDA_POOL_MAX_IDLE_PER_HOST")?;
+        let idle_timeout = read_env_var("LAMBDA_POOL_IDLE_TIMEOUT")?.map(Duration::from_millis);
+        Ok(HttpPoolConfig { max_idle_per_host, idle_timeout })
+    }
+}
+
+fn read_env_var<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some)
+            .map_err(|_| KnownHandledErrors::InvalidEnvironmentVariable(name.to_string())),
+        Err(_) => Ok(None)
+    }
+}
+
+/// Hands out `LambdaClient`s to listeners, along with generic clients of the AWS services
+/// lacking a dedicated client (SQS, SNS...). Targets sharing the same region, endpoint
+/// and role share the same client, and every client shares the same HTTP connection
+/// pool and default credentials, so credentials aren't refreshed once per subscriber.
+#[derive(Clone)]
+pub struct LambdaClientRegistry {
+    dispatcher: Arc<HttpClient>,
+    default_credentials: Arc<DefaultCredentialsProvider>,
+    clients: Arc<Mutex<HashMap<ClientKey, LambdaClient>>>,
+    service_clients: Arc<Mutex<HashMap<ClientKey, Client>>>
+}
+
+impl LambdaClientRegistry {
+
+    pub fn create(pool: HttpPoolConfig) -> Result<Self> {
+        info!("Lambda HTTP connection pool: {:?}", &pool);
+        let mut builder = hyper::Client::builder();
+        if let Some(max_idle_per_host) = pool.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle_per_host);
+        }
+        if let Some(idle_timeout) = pool.idle_timeout {
+            builder.pool_idle_timeout(idle_timeout);
+        }
+
+        Ok(LambdaClientRegistry {
+            dispatcher: Arc::new(HttpClient::from_builder(builder, HttpsConnector::new())),
+            default_credentials: Arc::new(DefaultCredentialsProvider::new()?),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            service_clients: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    /// Retrieves a `LambdaClient` able to reach `target_function`, honouring its
+    /// region, custom endpoint and role. Falls back to the AWS defaults otherwise.
+    pub fn client_for(&self, target_function: &TargetFunction) -> Result<LambdaClient> {
+        let key = ClientKey::of(target_function)?;
+
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone())
+        }
+
+        let client = self.create_client(&key)?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Retrieves a client able to sign and dispatch requests to any AWS service on behalf of
+    /// `target_function`, along with the region (or custom endpoint) requests should be sent to.
+    pub fn service_client_for(&self, target_function: &TargetFunction) -> Result<(Client, Region)> {
+        let key = ClientKey::of(target_function)?;
+
+        let mut clients = self.service_clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok((client.clone(), key.region))
+        }
+
+        let dispatcher = Arc::clone(&self.dispatcher);
+        let client = match &key.role {
+            None => Client::new_with(Arc::clone(&self.default_credentials), dispatcher),
+            Some((role_arn, sts_region)) => {
+                let assume_role = AssumeRoleCredentialsProvider::new(role_arn.clone(), sts_region.clone());
+                Client::new_with(AutoRefreshingProvider::new(assume_role)?, dispatcher)
+            }
+        };
+        let region = key.region.clone();
+        clients.insert(key, client.clone());
+        Ok((client, region))
+    }
+
+    fn create_client(&self, key: &ClientKey) -> Result<LambdaClient> {
+        let dispatcher = Arc::clone(&self.dispatcher);
+        let region = key.region.clone();
+        let client = match &key.role {
+            None => LambdaClient::new_with(dispatcher, Arc::clone(&self.default_credentials), region),
+            Some((role_arn, sts_region)) => {
+                let assume_role = AssumeRoleCredentialsProvider::new(role_arn.clone(), sts_region.clone());
+                LambdaClient::new_with(dispatcher, AutoRefreshingProvider::new(assume_role)?, region)
+            }
+        };
+        Ok(client)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+}
+
+fn region_of(target_function: &TargetFunction) -> Result<Region> {
+    Ok(with_endpoint(named_region_of(target_function)?, &target_function.endpoint_url))
+}
+
+/// The region roles are assumed through. Unlike `region_of`, it ignores `endpoint_url`,
+/// which points to the target service rather than to STS.
+fn sts_region_of(target_function: &TargetFunction) -> Result<Region> {
+    Ok(with_endpoint(named_region_of(target_function)?, &target_function.sts_endpoint_url))
+}
+
+fn named_region_of(target_function: &TargetFunction) -> Result<Region> {
+    Ok(match &target_function.region {
+        Some(name) => name.parse()?,
+        None => Region::default()
+    })
+}
+
+fn with_endpoint(region: Region, endpoint_url: &Option<String>) -> Region {
+    match endpoint_url {
+        Some(endpoint) => Region::Custom { name: region.name().to_string(), endpoint: endpoint.clone() },
+        None => region
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusoto_core::Region;
+
+    use crate::conf::TargetFunction;
+
+    use super::{HttpPoolConfig, LambdaClientRegistry, region_of, sts_region_of};
+
+    #[test]
+    fn should_point_to_custom_endpoint_within_the_configured_region() {
+        let target_function = TargetFunction {
+            name: "user_deleted".to_string(),
+            region: Some("ap-southeast-2".to_string()),
+            endpoint_url: Some("http://localhost:9001".to_string()),
+            ..TargetFunction::default()
+        };
+
+        let expected_region = Region::Custom {
+            name: "ap-southeast-2".to_string(),
+            endpoint: "http://localhost:9001".to_string()
+        };
+        assert_eq!(expected_region, region_of(&target_function).unwrap());
+        assert_eq!(Region::ApSoutheast2, sts_region_of(&target_function).unwrap());
+
+        let target_function = TargetFunction { sts_endpoint_url: Some("http://localhost:9002".to_string()), ..target_function };
+        let expected_sts_region = Region::Custom {
+            name: "ap-southeast-2".to_string(),
+            endpoint: "http://localhost:9002".to_string()
+        };
+        assert_eq!(expected_sts_region, sts_region_of(&target_function).unwrap());
+    }
+
+    #[test]
+    fn should_reject_unknown_regions() {
+        let target_function = TargetFunction { region: Some("mars-east-1".to_string()), ..TargetFunction::default() };
+        assert!(region_of(&target_function).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_share_clients_among_targets_with_the_same_region_and_role() {
+        let registry = LambdaClientRegistry::create(HttpPoolConfig::default()).unwrap();
+        let in_sydney = |name: &str| TargetFunction {
+            name: name.to_string(),
+            region: Some("ap-southeast-2".to_string()),
+            ..TargetFunction::default()
+        };
+
+        registry.client_for(&in_sydney("user_deleted")).unwrap();
+        registry.client_for(&in_sydney("user_updated")).unwrap();
+        assert_eq!(1, registry.len());
+
+        let cross_account = TargetFunction {
+            role_arn: Some("arn:aws:iam::123456789012:role/malka".to_string()),
+            ..in_sydney("user_deleted")
+        };
+        registry.client_for(&cross_account).unwrap();
+        assert_eq!(2, registry.len());
+    }
+}
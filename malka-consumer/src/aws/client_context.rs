@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::kafka::consumer::InFlightRecord;
+
+/// Lambda rejects client contexts bigger than this (after being base64 encoded).
+const MAX_ENCODED_CLIENT_CONTEXT_SIZE: usize = 3583;
+
+/// The consumer which records are being sent on behalf of.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerIdentity {
+    pub group_id: String,
+    pub group_instance_id: String
+}
+
+/// The Lambda client context. Functions can read it through `context.client_context.custom`.
+#[derive(Serialize)]
+struct ClientContext<'a> {
+    custom: KafkaProvenance<'a>
+}
+
+/// Where the records sent in a single invocation came from.
+#[derive(Serialize)]
+struct KafkaProvenance<'a> {
+    group_id: &'a str,
+    group_instance_id: &'a str,
+    topic: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    partitions: Vec<OffsetRange>
+}
+
+/// The offsets, inclusive, of the records sent from a single partition.
+#[derive(Serialize, Debug, PartialEq)]
+struct OffsetRange {
+    partition: i32,
+    first_offset: i64,
+    last_offset: i64
+}
+
+/// Creates the base64 encoded client context of an invocation sending `records`.
+/// Offset ranges are left out whenever they'd make the context bigger than Lambda accepts.
+pub fn encode_client_context(consumer: &ConsumerIdentity, records: &[InFlightRecord]) -> Option<String> {
+    let topic = records.first()?.topic.as_str();
+    let mut context = ClientContext {
+        custom: KafkaProvenance {
+            group_id: &consumer.group_id,
+            group_instance_id: &consumer.group_instance_id,
+            topic,
+            partitions: offset_ranges_of(records)
+        }
+    };
+
+    let encoded = encode(&context);
+    if encoded.len() <= MAX_ENCODED_CLIENT_CONTEXT_SIZE {
+        return Some(encoded)
+    }
+
+    warn!("[{}] Client context is too big. Leaving offset ranges out of it.", &consumer.group_instance_id);
+    context.custom.partitions.clear();
+    Some(encode(&context))
+}
+
+fn offset_ranges_of(records: &[InFlightRecord]) -> Vec<OffsetRange> {
+    let mut ranges: BTreeMap<i32, OffsetRange> = BTreeMap::new();
+    for record in records {
+        let range = ranges.entry(record.partition).or_insert(OffsetRange {
+            partition: record.partition, first_offset: record.offset, last_offset: record.offset
+        });
+        range.first_offset = range.first_offset.min(record.offset);
+        range.last_offset = range.last_offset.max(record.offset);
+    }
+    ranges.into_values().collect()
+}
+
+fn encode(context: &ClientContext) -> String {
+    let json = serde_json::to_vec(context).expect("Failed to serialize client context");
+    base64::encode(json)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kafka::consumer::InFlightRecord;
+
+    use super::{ConsumerIdentity, encode_client_context};
+
+    fn record(partition: i32, offset: i64) -> InFlightRecord {
+        InFlightRecord { topic: "user.delete".to_string(), partition, offset, ..InFlightRecord::create(None, None) }
+    }
+
+    #[test]
+    fn should_describe_where_records_came_from() {
+        let consumer = ConsumerIdentity {
+            group_id: "user.delete-user_deleted".to_string(),
+            group_instance_id: "user.delete-user_deleted-0".to_string()
+        };
+        let records = [record(1, 10), record(0, 7), record(1, 11), record(0, 5)];
+
+        let encoded = encode_client_context(&consumer, &records).unwrap();
+        let decoded = String::from_utf8(base64::decode(encoded).unwrap()).unwrap();
+        let expected = r#"{"custom":{"group_id":"user.delete-user_deleted","group_instance_id":"user.delete-user_deleted-0","topic":"user.delete","partitions":[{"partition":0,"first_offset":5,"last_offset":7},{"partition":1,"first_offset":10,"last_offset":11}]}}"#;
+        assert_eq!(expected, decoded);
+    }
+
+    #[test]
+    fn should_leave_offset_ranges_out_of_oversized_contexts() {
+        let consumer = ConsumerIdentity {
+            group_id: "user.delete-user_deleted".to_string(),
+            group_instance_id: "user.delete-user_deleted-0".to_string()
+        };
+        let records: Vec<InFlightRecord> = (0..200).map(|partition| record(partition, 1)).collect();
+
+        let encoded = encode_client_context(&consumer, &records).unwrap();
+        let decoded = String::from_utf8(base64::decode(encoded).unwrap()).unwrap();
+        assert!(!decoded.contains("partitions"));
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusoto_core::{Client, Region};
+use rusoto_core::credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+use rusoto_core::param::{Params, ServiceParams};
+use rusoto_core::signature::SignedRequest;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::aws::query::encode_form;
+
+const STS_API_VERSION: &str = "2011-06-15";
+const ROLE_SESSION_NAME: &str = "malka";
+const CREDENTIAL_FIELDS: [&str; 4] = ["AccessKeyId", "SecretAccessKey", "SessionToken", "Expiration"];
+
+/// Provides temporary credentials obtained by assuming `role_arn` through AWS STS.
+/// The role is assumed with the default credentials (env, profile, instance role...).
+/// Wrap it within an `AutoRefreshingProvider`, so credentials are only renewed once expired.
+pub struct AssumeRoleCredentialsProvider {
+    role_arn: String,
+    region: Region,
+    client: Client
+}
+
+impl AssumeRoleCredentialsProvider {
+    pub fn new(role_arn: String, region: Region) -> Self {
+        AssumeRoleCredentialsProvider { role_arn, region, client: Client::shared() }
+    }
+
+    fn create_request(&self) -> SignedRequest {
+        let mut params = Params::new();
+        params.put("Action", "AssumeRole");
+        params.put("Version", STS_API_VERSION);
+        params.put("RoleArn", &self.role_arn);
+        params.put("RoleSessionName", ROLE_SESSION_NAME);
+
+        let mut request = SignedRequest::new("POST", "sts", &self.region, "/");
+        request.set_content_type("application/x-www-form-urlencoded".to_string());
+        request.set_payload(Some(encode_form(&params)));
+        request
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for AssumeRoleCredentialsProvider {
+
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let mut response = self.client.sign_and_dispatch(self.create_request()).await
+            .map_err(|cause| CredentialsError::new(format!("Failed to assume role {}: {:?}", &self.role_arn, cause)))?;
+        let response = response.buffer().await
+            .map_err(|cause| CredentialsError::new(format!("Failed to assume role {}: {}", &self.role_arn, cause)))?;
+
+        if !response.status.is_success() {
+            return Err(CredentialsError::new(format!(
+                "Failed to assume role {}: {}", &self.role_arn, response.body_as_str())))
+        }
+        parse_assume_role_response(response.body_as_str())
+    }
+}
+
+/// Reads the credentials out of an STS `AssumeRoleResponse` document.
+fn parse_assume_role_response(body: &str) -> Result<AwsCredentials, CredentialsError> {
+    let mut fields = HashMap::new();
+    let mut current_field = None;
+
+    for event in EventReader::from_str(body) {
+        match event.map_err(|cause| CredentialsError::new(format!("Invalid AssumeRole response: {}", cause)))? {
+            XmlEvent::StartElement { name, .. } => {
+                current_field = CREDENTIAL_FIELDS.iter().find(|field| **field == name.local_name);
+            },
+            XmlEvent::Characters(value) => if let Some(field) = current_field {
+                fields.insert(*field, value);
+            },
+            XmlEvent::EndElement { .. } => current_field = None,
+            _ => {}
+        }
+    }
+
+    let mut field = |name: &str| fields.remove(name)
+        .ok_or_else(|| CredentialsError::new(format!("AssumeRole response has no {}", name)));
+    let access_key_id = field("AccessKeyId")?;
+    let secret_access_key = field("SecretAccessKey")?;
+    let session_token = field("SessionToken")?;
+    let expiration = DateTime::parse_from_rfc3339(&field("Expiration")?)
+        .map_err(|cause| CredentialsError::new(format!("Invalid credentials expiration: {}", cause)))?;
+
+    Ok(AwsCredentials::new(access_key_id, secret_access_key, Some(session_token), Some(expiration.with_timezone(&Utc))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_assume_role_response;
+
+    #[test]
+    fn should_read_credentials_from_assume_role_response() {
+        let body = r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+          <AssumeRoleResult>
+            <Credentials>
+              <AccessKeyId>ASIAEXAMPLE</AccessKeyId>
+              <SecretAccessKey>secret</SecretAccessKey>
+              <SessionToken>token</SessionToken>
+              <Expiration>2021-03-10T10:00:00Z</Expiration>
+            </Credentials>
+          </AssumeRoleResult>
+        </AssumeRoleResponse>"#;
+
+        let credentials = parse_assume_role_response(body).unwrap();
+        assert_eq!("ASIAEXAMPLE", credentials.aws_access_key_id());
+        assert_eq!("secret", credentials.aws_secret_access_key());
+        assert_eq!(Some("token".to_string()), credentials.token().clone());
+        assert_eq!("2021-03-10T10:00:00+00:00", credentials.expires_at().unwrap().to_rfc3339());
+    }
+
+    #[test]
+    fn should_fail_when_credentials_are_missing() {
+        let body = "<AssumeRoleResponse><AssumeRoleResult></AssumeRoleResult></AssumeRoleResponse>";
+        assert!(parse_assume_role_response(body).is_err());
+    }
+}
@@ -1,43 +1,93 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 use log::error;
-use rusoto_core::Region;
-use rusoto_lambda::{InvocationRequest, Lambda, LambdaClient};
+use rusoto_core::RusotoError;
+use rusoto_lambda::{InvocationRequest, InvokeError, Lambda, LambdaClient};
 
+use crate::aws::client_context::{ConsumerIdentity, encode_client_context};
+use crate::conf::{InvocationType, TargetFunction};
 use crate::kafka::consumer::{
     InFlightRecord, KafkaConsumerListener, KafkaConsumerResult
 };
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::PayloadEncoder;
+
+/// Payload limit of synchronous (`RequestResponse` and `DryRun`) invocations.
+const MAX_SYNC_PAYLOAD_SIZE: usize = 6 * 1024 * 1024;
+/// Payload limit of asynchronous (`Event`) invocations.
+const MAX_ASYNC_PAYLOAD_SIZE: usize = 256 * 1024;
 
 /// A `KafkaConsumerListener` implementation that invokes AWS Lambda functions.
+/// When invoked asynchronously (`Event`), records are considered consumed as
+/// soon as Lambda accepts them, regardless of how long the function takes to run.
+/// When only validated (`DryRun`), records are never committed.
+/// Every invocation carries the records provenance within its client context.
 pub struct AwsLambdaKafkaConsumerListener {
+    consumer: ConsumerIdentity,
+    payload_encoder: PayloadEncoder,
     function_name: String,
+    qualifier: Option<String>,
+    invocation_type: InvocationType,
     lambda_client: LambdaClient
 }
 
 impl AwsLambdaKafkaConsumerListener {
-    pub fn create(function_name: String) -> Self {
-        let region: Region = Default::default();
+    pub fn create(
+        target_function: TargetFunction, consumer: ConsumerIdentity,
+        payload_encoder: PayloadEncoder, lambda_client: LambdaClient
+    ) -> Self {
         AwsLambdaKafkaConsumerListener {
-            lambda_client: LambdaClient::new(region),
-            function_name
+            consumer,
+            payload_encoder,
+            lambda_client,
+            function_name: target_function.name,
+            qualifier: target_function.qualifier,
+            invocation_type: target_function.invocation_type
         }
     }
+
+    fn classify_failure(&self, cause: RusotoError<InvokeError>) -> KafkaConsumerError {
+        let msg = format!("Failed to invoke function {}: {}", &self.function_name, cause);
+        let classify: fn(FailureCause) -> KafkaConsumerError = match &cause {
+            RusotoError::Service(failure) => match failure {
+                InvokeError::TooManyRequests(_)
+                | InvokeError::EC2Throttled(_) => KafkaConsumerError::Throttled,
+                InvokeError::RequestTooLarge(_) => KafkaConsumerError::PayloadTooLarge,
+                InvokeError::InvalidRequestContent(_)
+                | InvokeError::InvalidParameterValue(_)
+                | InvokeError::UnsupportedMediaType(_) => KafkaConsumerError::InvalidPayload,
+                InvokeError::ResourceNotFound(_) => KafkaConsumerError::NotFound,
+                InvokeError::EC2AccessDenied(_)
+                | InvokeError::KMSAccessDenied(_) => KafkaConsumerError::PermissionDenied,
+                _ => KafkaConsumerError::Network
+            },
+            RusotoError::Validation(_) => KafkaConsumerError::InvalidPayload,
+            RusotoError::Unknown(response) => match response.status.as_u16() {
+                429 => KafkaConsumerError::Throttled,
+                413 => KafkaConsumerError::PayloadTooLarge,
+                404 => KafkaConsumerError::NotFound,
+                401 | 403 => KafkaConsumerError::PermissionDenied,
+                _ => KafkaConsumerError::Network
+            },
+            _ => KafkaConsumerError::Network
+        };
+        classify(FailureCause::with_source(msg, cause))
+    }
 }
 
 #[async_trait]
 impl KafkaConsumerListener
  for AwsLambdaKafkaConsumerListener {
 
-    async fn consume(&self, records: Vec<InFlightRecord>) -> KafkaConsumerResult {
-        let json_string = serde_json::to_string(&records).expect("Failed to serialize message");
-        let json_bytes = Bytes::from(json_string);
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let json_bytes = Bytes::from(self.payload_encoder.encode(records));
         let result = self.lambda_client.invoke(InvocationRequest {
             function_name: self.function_name.clone(),
             payload: Some(json_bytes),
-            client_context: None,
-            invocation_type: None,
+            client_context: encode_client_context(&self.consumer, records),
+            invocation_type: Some(self.invocation_type.to_string()),
             log_type: None,
-            qualifier: None
+            qualifier: self.qualifier.clone()
         }).await;
 
         match result {
@@ -47,19 +97,46 @@ impl KafkaConsumerListener
                 }
                 KafkaConsumerResult::Succeeded
             },
-            Err(cause) => {
-                let msg = format!("Failed to invoke function {}: {}", &self.function_name, cause);
-                KafkaConsumerResult::Failed(msg)
-            }
+            Err(cause) => KafkaConsumerResult::Failed(self.classify_failure(cause))
+        }
+    }
+
+    fn max_payload_size(&self) -> Option<usize> {
+        match self.invocation_type {
+            InvocationType::Event => Some(MAX_ASYNC_PAYLOAD_SIZE),
+            InvocationType::RequestResponse | InvocationType::DryRun => Some(MAX_SYNC_PAYLOAD_SIZE)
         }
     }
+
+    fn commits_offsets(&self) -> bool {
+        self.invocation_type != InvocationType::DryRun
+    }
 }
 
 #[cfg(test)]
 mod test {
 
+    use rusoto_core::Region;
+
+    use crate::conf::PayloadFormat;
+
     use super::*;
 
+    fn consumer() -> ConsumerIdentity {
+        ConsumerIdentity {
+            group_id: "user.delete-user_deleted".to_string(),
+            group_instance_id: "user.delete-user_deleted-0".to_string()
+        }
+    }
+
+    fn payload_encoder() -> PayloadEncoder {
+        PayloadEncoder::create(PayloadFormat::Array, "localhost:9092".to_string())
+    }
+
+    fn lambda_client() -> LambdaClient {
+        LambdaClient::new(Region::default())
+    }
+
     #[tokio::test]
     #[ignore]
     async fn should_invoke_lambda() {
@@ -67,8 +144,44 @@ mod test {
         let value = "{'hello':'world'}".as_bytes();
         let record = InFlightRecord::create(Some(key),Some(value));
 
-        let consumer = AwsLambdaKafkaConsumerListener::create("user_deleted".to_string());
-        let result = consumer.consume(vec!(record)).await;
+        let consumer = AwsLambdaKafkaConsumerListener::create(TargetFunction::from("user_deleted".to_string()), consumer(), payload_encoder(), lambda_client());
+        let result = consumer.consume(&[record]).await;
         println!("Result: {:?}", result)
     }
+
+    #[tokio::test]
+    async fn should_classify_invocation_errors() {
+        let consumer = AwsLambdaKafkaConsumerListener::create(TargetFunction::from("user_deleted".to_string()), consumer(), payload_encoder(), lambda_client());
+
+        let throttled = RusotoError::Service(InvokeError::TooManyRequests("Rate exceeded".to_string()));
+        assert_eq!("throttled", consumer.classify_failure(throttled).label());
+
+        let too_large = RusotoError::Service(InvokeError::RequestTooLarge("Request too large".to_string()));
+        assert_eq!("payload_too_large", consumer.classify_failure(too_large).label());
+
+        let not_found = RusotoError::Service(InvokeError::ResourceNotFound("Function not found".to_string()));
+        assert_eq!("not_found", consumer.classify_failure(not_found).label());
+    }
+
+    #[tokio::test]
+    async fn should_limit_payload_size_according_to_invocation_type() {
+        let sync_consumer = AwsLambdaKafkaConsumerListener::create(TargetFunction::from("user_deleted".to_string()), consumer(), payload_encoder(), lambda_client());
+        assert_eq!(Some(MAX_SYNC_PAYLOAD_SIZE), sync_consumer.max_payload_size());
+
+        let async_consumer = AwsLambdaKafkaConsumerListener::create(TargetFunction {
+            name: "user_deleted".to_string(),
+            invocation_type: InvocationType::Event,
+            ..TargetFunction::default()
+        }, consumer(), payload_encoder(), lambda_client());
+        assert_eq!(Some(MAX_ASYNC_PAYLOAD_SIZE), async_consumer.max_payload_size());
+        assert!(async_consumer.commits_offsets());
+
+        let dry_run_consumer = AwsLambdaKafkaConsumerListener::create(TargetFunction {
+            name: "user_deleted".to_string(),
+            invocation_type: InvocationType::DryRun,
+            ..TargetFunction::default()
+        }, consumer(), payload_encoder(), lambda_client());
+        assert_eq!(Some(MAX_SYNC_PAYLOAD_SIZE), dry_run_consumer.max_payload_size());
+        assert!(!dry_run_consumer.commits_offsets());
+    }
 }
\ No newline at end of file
@@ -1 +1,7 @@
-pub mod lambda_publisher;
\ No newline at end of file
+pub mod lambda_publisher;
+pub mod batch_publisher;
+pub mod client;
+pub mod client_context;
+pub mod credentials;
+pub mod query;
+pub mod step_functions_publisher;
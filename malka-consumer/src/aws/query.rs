@@ -0,0 +1,35 @@
+use rusoto_core::param::Params;
+
+/// Encodes the parameters of AWS Query API requests (STS, SQS, SNS...) as a form body.
+pub fn encode_form(params: &Params) -> String {
+    params.iter()
+        .map(|(key, value)| {
+            let value = value.as_deref().unwrap_or_default();
+            format!("{}={}", urlencode(key), urlencode(value))
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn urlencode(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use rusoto_core::param::{Params, ServiceParams};
+
+    use super::encode_form;
+
+    #[test]
+    fn should_encode_request_parameters() {
+        let mut params = Params::new();
+        params.put("RoleArn", "arn:aws:iam::123456789012:role/malka");
+        assert_eq!("RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fmalka", encode_form(&params));
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::{error, info};
+use rusoto_core::{Client, Region};
+use rusoto_core::signature::SignedRequest;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::conf::StepFunctionsConfig;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::PayloadEncoder;
+
+/// Max size, in bytes, of an execution input.
+const MAX_INPUT_SIZE: usize = 256 * 1024;
+/// Max length of an execution name.
+const MAX_EXECUTION_NAME_LENGTH: usize = 80;
+
+/// A `KafkaConsumerListener` implementation that starts a Step Functions state machine
+/// execution with the records as input. Executions are named after the offsets they
+/// were started for, so the very same records delivered again won't start another
+/// execution. As redelivered batches might not hold the same records, dispatching
+/// records one at a time (`per_record`) is the only way to make it fully idempotent.
+pub struct StepFunctionsKafkaConsumerListener {
+    state_machine_arn: String,
+    payload_encoder: PayloadEncoder,
+    client: Client,
+    region: Region
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StartExecutionRequest<'a> {
+    state_machine_arn: &'a str,
+    name: String,
+    input: String
+}
+
+/// The body of Step Functions failed responses.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(rename = "__type", default)]
+    error_type: String,
+    #[serde(alias = "Message", default)]
+    message: String
+}
+
+impl StepFunctionsKafkaConsumerListener {
+    pub fn create(config: StepFunctionsConfig, payload_encoder: PayloadEncoder, client: Client, region: Region) -> Self {
+        StepFunctionsKafkaConsumerListener {
+            state_machine_arn: config.state_machine_arn,
+            payload_encoder, client, region
+        }
+    }
+
+    fn create_request(&self, name: String, records: &[InFlightRecord]) -> SignedRequest {
+        let body = StartExecutionRequest {
+            state_machine_arn: &self.state_machine_arn,
+            name,
+            input: String::from_utf8_lossy(&self.payload_encoder.encode(records)).into_owned()
+        };
+
+        let mut request = SignedRequest::new("POST", "states", &self.region, "/");
+        request.set_content_type("application/x-amz-json-1.0".to_string());
+        request.add_header("x-amz-target", "AWSStepFunctions.StartExecution");
+        request.set_payload(Some(serde_json::to_vec(&body).expect("Failed to serialize request")));
+        request
+    }
+
+    fn classify_failure(&self, name: &str, is_client_error: bool, body: &str) -> KafkaConsumerResult {
+        let response: ErrorResponse = serde_json::from_str(body)
+            .unwrap_or(ErrorResponse { error_type: String::new(), message: body.to_string() });
+        // Error types might be prefixed by their namespace (e.g. `com.amazonaws.swf.service.v2.model#`).
+        let error_type = response.error_type.rsplit('#').next().unwrap_or_default();
+        if error_type == "ExecutionAlreadyExists" {
+            info!("Execution {} of {} was already started. Moving on.", name, &self.state_machine_arn);
+            return KafkaConsumerResult::Succeeded
+        }
+
+        error!("Failed to start execution {} of {}: {} {}", name, &self.state_machine_arn, error_type, &response.message);
+        let classify: fn(FailureCause) -> KafkaConsumerError = match error_type {
+            "ExecutionLimitExceeded" | "ThrottlingException" => KafkaConsumerError::Throttled,
+            "InvalidExecutionInput" | "InvalidName" => KafkaConsumerError::InvalidPayload,
+            "StateMachineDoesNotExist" | "StateMachineDeleting" | "InvalidArn" => KafkaConsumerError::NotFound,
+            "AccessDeniedException" | "UnrecognizedClientException" => KafkaConsumerError::PermissionDenied,
+            _ if is_client_error => KafkaConsumerError::InvalidPayload,
+            _ => KafkaConsumerError::Network
+        };
+        KafkaConsumerResult::Failed(classify(FailureCause::new(format!(
+            "Failed to start execution {} of {}: {} {}", name, &self.state_machine_arn, error_type, &response.message))))
+    }
+}
+
+/// Names an execution after the offsets of `records`, e.g. `user-delete-0_5-7.1_10`.
+/// Names that would be too long are replaced by a digest of their offsets.
+fn execution_name_of(records: &[InFlightRecord]) -> String {
+    let mut ranges: BTreeMap<i32, (i64, i64)> = BTreeMap::new();
+    for record in records {
+        let range = ranges.entry(record.partition).or_insert((record.offset, record.offset));
+        range.0 = range.0.min(record.offset);
+        range.1 = range.1.max(record.offset);
+    }
+
+    let offsets = ranges.iter()
+        .map(|(partition, (first, last))| match first == last {
+            true => format!("{}_{}", partition, first),
+            false => format!("{}_{}-{}", partition, first, last)
+        })
+        .collect::<Vec<String>>()
+        .join(".");
+    let topic: String = records.first().map(|record| record.topic.as_str()).unwrap_or_default()
+        .chars()
+        .map(|char| if char.is_ascii_alphanumeric() || char == '-' || char == '_' { char } else { '-' })
+        .collect();
+
+    let name = format!("{}-{}", topic, offsets);
+    if name.len() <= MAX_EXECUTION_NAME_LENGTH {
+        return name
+    }
+
+    let digest = hex::encode(Sha256::digest(name.as_bytes()));
+    let topic_length = MAX_EXECUTION_NAME_LENGTH - digest.len() - 1;
+    format!("{}-{}", &topic[..topic.len().min(topic_length)], digest)
+}
+
+#[async_trait]
+impl KafkaConsumerListener for StepFunctionsKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let name = execution_name_of(records);
+        let request = self.create_request(name.clone(), records);
+
+        let response = match self.client.sign_and_dispatch(request).await {
+            Ok(mut response) => response.buffer().await
+                .map_err(|cause| FailureCause::with_source(format!("Failed to reach {}", &self.state_machine_arn), cause)),
+            Err(cause) => Err(FailureCause::new(format!("Failed to reach {}: {:?}", &self.state_machine_arn, cause)))
+        };
+
+        match response {
+            Ok(response) if response.status.is_success() => KafkaConsumerResult::Succeeded,
+            Ok(response) => self.classify_failure(&name, response.status.is_client_error(), response.body_as_str()),
+            Err(cause) => KafkaConsumerResult::Failed(KafkaConsumerError::Network(cause))
+        }
+    }
+
+    fn max_payload_size(&self) -> Option<usize> {
+        Some(MAX_INPUT_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusoto_core::{Client, HttpClient, Region};
+    use rusoto_core::credential::StaticProvider;
+
+    use crate::conf::{PayloadFormat, StepFunctionsConfig};
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerResult};
+    use crate::kafka::error::KafkaConsumerError;
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::{execution_name_of, StepFunctionsKafkaConsumerListener};
+
+    fn record(partition: i32, offset: i64) -> InFlightRecord {
+        InFlightRecord { topic: "user.delete".to_string(), partition, offset, ..InFlightRecord::create(None, None) }
+    }
+
+    #[test]
+    fn should_name_executions_after_record_offsets() {
+        let records = [record(1, 10), record(0, 7), record(0, 5)];
+        assert_eq!("user-delete-0_5-7.1_10", execution_name_of(&records));
+
+        let records: Vec<InFlightRecord> = (0..20).map(|partition| record(partition, 1000)).collect();
+        let name = execution_name_of(&records);
+        assert!(name.len() <= 80);
+        assert!(name.starts_with("user-delete-"));
+        assert_eq!(name, execution_name_of(&records));
+    }
+
+    #[test]
+    fn should_consider_already_started_executions_as_succeeded() {
+        let client = Client::new_with(StaticProvider::new_minimal("key".to_string(), "secret".to_string()), HttpClient::new().unwrap());
+        let config = StepFunctionsConfig { state_machine_arn: "arn:aws:states:us-east-1:000000000000:stateMachine:users".to_string() };
+        let listener = StepFunctionsKafkaConsumerListener::create(
+            config, PayloadEncoder::create(PayloadFormat::Array, String::new()), client, Region::UsEast1);
+
+        let already_exists = r#"{"__type":"ExecutionAlreadyExists","message":"Execution Already Exists"}"#;
+        assert_eq!(KafkaConsumerResult::Succeeded, listener.classify_failure("users-0_1", true, already_exists));
+
+        let throttled = r#"{"__type":"com.amazonaws.swf.service.v2.model#ExecutionLimitExceeded"}"#;
+        let result = listener.classify_failure("users-0_1", true, throttled);
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Throttled(_))));
+
+        let result = listener.classify_failure("users-0_1", false, "Service Unavailable");
+        assert!(matches!(result, KafkaConsumerResult::Failed(failure) if failure.is_retriable()));
+    }
+}
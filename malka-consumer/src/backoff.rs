@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// An exponential backoff. The delay doubles after each attempt
+/// until it reaches `max`, and goes back to `initial` once reset.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, attempts: 0 }
+    }
+
+    /// Returns how long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempts);
+        self.attempts = self.attempts.saturating_add(1);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn should_double_the_delay_until_max_is_reached() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+
+        assert_eq!(Duration::from_millis(100), backoff.next_delay());
+        assert_eq!(Duration::from_millis(200), backoff.next_delay());
+        assert_eq!(Duration::from_millis(400), backoff.next_delay());
+        assert_eq!(Duration::from_millis(500), backoff.next_delay());
+
+        backoff.reset();
+        assert_eq!(Duration::from_millis(100), backoff.next_delay());
+    }
+}
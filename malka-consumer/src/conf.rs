@@ -1,10 +1,18 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use rdkafka::ClientConfig;
 use std::env;
 use log::{info};
 use rdkafka::config::RDKafkaLogLevel;
 
+use crate::error::{KnownHandledErrors, Result};
+
+/// Identifies the consumer group shared by all target functions of a subscription.
+/// Target functions can't have it as id, as their consumer groups would be mixed up.
+pub const SHARED_CONSUMER_GROUP_NAME: &str = "shared";
+
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct SubscriptionConfig {
     pub topic_name: String,
@@ -12,40 +20,629 @@ pub struct SubscriptionConfig {
     pub topic_number_of_consumers: u32,
     #[serde(default = "max_buffer_size")]
     pub topic_max_buffer_size: usize,
+    #[serde(default = "max_buffer_bytes")]
+    pub topic_max_buffer_bytes: usize,
     #[serde(default = "max_buffer_await_time_ms")]
     pub topic_max_buffer_await_time: u64,
+    #[serde(default = "throttling_backoff_time_ms")]
+    pub topic_throttling_backoff_time: u64,
+    #[serde(default = "max_throttling_backoff_time_ms")]
+    pub topic_max_throttling_backoff_time: u64,
+    #[serde(default)]
+    pub oversized_record_policy: OversizedRecordPolicy,
+    #[serde(default)]
+    pub dead_letter_topic: Option<String>,
+    #[serde(default)]
+    pub payload_format: PayloadFormat,
+    #[serde(default)]
+    pub dispatch_mode: DispatchMode,
+    #[serde(default)]
+    pub consumer_group_mode: ConsumerGroupMode,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Records matching none of these filters are committed without being delivered.
+    /// Every record is delivered when there are no filters.
+    #[serde(default)]
+    pub filters: Vec<RecordFilterConfig>,
+    /// Picks the single target function each record is delivered to. See `RecordRouter`.
+    /// Records go to every target function when there are no routes. Requires a `shared`
+    /// `consumer_group_mode`, as routed records are batched by a single consumer group.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Path to a WebAssembly module every record goes through before being delivered.
+    /// See `WasmRecordTransformer`.
+    #[serde(default)]
+    pub wasm_transform: Option<String>,
+    /// Fuel each record may consume within the `wasm_transform` module, roughly one unit
+    /// per executed instruction. Records running out of it fail to be transformed.
+    #[serde(default = "wasm_transform_fuel")]
+    pub wasm_transform_fuel: u64,
     #[serde(default)]
     pub consumer_configuration: Option<HashMap<String, String>>,
-    pub target_functions: Vec<String>
+    /// The consumer group id. Defaults to `{topic}-{function}`. Subscriptions with many
+    /// target functions, each with its own consumer group, set it through their `overrides`.
+    #[serde(default)]
+    pub group_id: Option<String>,
+    /// Template of the static group instance ids. See `render_group_instance_id`.
+    #[serde(default = "group_instance_id_template")]
+    pub group_instance_id_template: String,
+    #[serde(deserialize_with = "deserialize_target_functions")]
+    pub target_functions: Vec<TargetFunction>
+}
+
+/// A function that will receive the consumed records. It can be either defined
+/// by its name alone, or by an object with its name and invocation details.
+/// Its `type` defines which listener hands the records to it (Lambda by default).
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TargetFunction {
+    /// The function name, or its full ARN.
+    pub name: String,
+    #[serde(rename = "type", default = "lambda_target_type")]
+    pub target_type: String,
+    /// The alias or version to invoke. Defaults to `$LATEST`.
+    #[serde(default)]
+    pub qualifier: Option<String>,
+    #[serde(default)]
+    pub invocation_type: InvocationType,
+    /// The AWS region the function lives in. Defaults to the region Malka is running in.
+    #[serde(default)]
+    pub region: Option<String>,
+    /// A custom Lambda endpoint (e.g. a local Lambda emulator).
+    #[serde(default)]
+    pub endpoint_url: Option<String>,
+    /// A role to be assumed before invoking the function (e.g. cross-account invocations).
+    #[serde(default)]
+    pub role_arn: Option<String>,
+    /// A custom STS endpoint the role is assumed through. Defaults to the STS endpoint
+    /// of the function region, even when `endpoint_url` is set.
+    #[serde(default)]
+    pub sts_endpoint_url: Option<String>,
+    #[serde(default)]
+    pub overrides: TargetFunctionOverrides,
+    /// Settings specific to the target `type` (e.g. `WebhookConfig`).
+    #[serde(flatten)]
+    pub settings: HashMap<String, serde_json::Value>
+}
+
+fn lambda_target_type() -> String { "lambda".to_string() }
+
+/// Settings of `webhook` targets: an HTTP endpoint records are POSTed to. Responses with `2xx` are considered
+/// successful, while `429` and `5xx` are retried.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// How long, in milliseconds, to wait for the endpoint to respond.
+    #[serde(default = "webhook_timeout_ms")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub auth: Option<WebhookAuth>
+}
+
+/// How Malka authenticates against webhooks.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WebhookAuth {
+    Bearer { token: String },
+    Basic { username: String, password: String },
+    /// Signs the payload with HMAC-SHA256, sending `sha256=<hex digest>` within `header`.
+    Hmac {
+        secret: String,
+        #[serde(default = "webhook_signature_header")]
+        header: String
+    }
+}
+
+/// Settings of `command` targets: an executable run once per batch. Records are written
+/// to its stdin, and its exit code tells whether they were consumed. See `CommandKafkaConsumerListener`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CommandConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// How long, in milliseconds, the command is allowed to run before being killed.
+    #[serde(default = "command_timeout_ms")]
+    pub timeout: u64
+}
+
+/// Settings of `grpc` targets: a service implementing `malka.v1.RecordConsumer` (see `proto/malka.proto`).
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// The service address, e.g. `https://users.internal:443`.
+    pub endpoint: String,
+    /// Metadata sent along with every call.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// The deadline, in milliseconds, of every call.
+    #[serde(default = "grpc_timeout_ms")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub tls: Option<GrpcTlsConfig>
+}
+
+/// How `https` gRPC endpoints are verified. Native root certificates are trusted by default.
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcTlsConfig {
+    /// Path to a PEM encoded certificate authority to trust.
+    #[serde(default)]
+    pub ca_certificate: Option<String>,
+    /// The name the server certificate is verified against. Defaults to the endpoint host.
+    #[serde(default)]
+    pub domain_name: Option<String>
+}
+
+/// Settings of `kafka` targets: a topic records are forwarded (produced) to.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaForwardConfig {
+    pub topic: String,
+    /// Producer settings, on top of the subscription ones (e.g. `bootstrap.servers` of another cluster).
+    #[serde(default)]
+    pub producer_configuration: HashMap<String, String>,
+    /// Re-keys records with the value of this header. Records without it keep their original key.
+    #[serde(default)]
+    pub key_header: Option<String>
+}
+
+/// Settings of `sqs` targets: a queue every record is sent to as a message.
+/// Its region, endpoint and role come from the target itself.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SqsConfig {
+    pub queue_url: String,
+    /// Required by FIFO queues. Messages are deduplicated by their record coordinates.
+    #[serde(default)]
+    pub message_group_id: Option<String>
+}
+
+/// Settings of `sns` targets: a topic every record is published to as a message.
+/// Its region, endpoint and role come from the target itself.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnsConfig {
+    pub topic_arn: String,
+    /// Required by FIFO topics. Messages are deduplicated by their record coordinates.
+    #[serde(default)]
+    pub message_group_id: Option<String>
+}
+
+/// Settings of `step_functions` targets: a state machine executed once per batch. Combine it
+/// with the `per_record` dispatch mode to have one execution per record.
+/// Its region, endpoint and role come from the target itself.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StepFunctionsConfig {
+    pub state_machine_arn: String
+}
+
+fn webhook_timeout_ms() -> u64 { 30000 }
+fn grpc_timeout_ms() -> u64 { 30000 }
+fn command_timeout_ms() -> u64 { 30000 }
+fn webhook_signature_header() -> String { "x-malka-signature".to_string() }
+
+/// Subscription settings that should be different for a single target function.
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct TargetFunctionOverrides {
+    pub topic_number_of_consumers: Option<u32>,
+    pub topic_max_buffer_size: Option<usize>,
+    pub topic_max_buffer_bytes: Option<usize>,
+    pub topic_max_buffer_await_time: Option<u64>,
+    pub dead_letter_topic: Option<String>,
+    pub dispatch_mode: Option<DispatchMode>,
+    pub group_id: Option<String>
+}
+
+/// How Lambda functions are invoked. Named after the AWS `InvocationType` values.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum InvocationType {
+    /// Waits for the function to handle the records.
+    #[default]
+    RequestResponse,
+    /// Hands the records to Lambda, which queues them for the function.
+    Event,
+    /// Only validates the invocation (e.g. its permissions and payload), without running
+    /// the function. Records are never committed, so they're consumed again once the
+    /// function is invoked for real.
+    DryRun
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TargetFunctionDefinition {
+    Name(String),
+    Detailed(Box<TargetFunction>)
+}
+
+fn deserialize_target_functions<'de, D>(deserializer: D) -> std::result::Result<Vec<TargetFunction>, D::Error>
+    where D: Deserializer<'de> {
+    let definitions = Vec::<TargetFunctionDefinition>::deserialize(deserializer)?;
+    Ok(definitions.into_iter().map(TargetFunction::from).collect())
+}
+
+impl From<TargetFunctionDefinition> for TargetFunction {
+    fn from(definition: TargetFunctionDefinition) -> Self {
+        match definition {
+            TargetFunctionDefinition::Name(name) => TargetFunction::from(name),
+            TargetFunctionDefinition::Detailed(target_function) => *target_function
+        }
+    }
+}
+
+impl Default for TargetFunction {
+    fn default() -> Self {
+        TargetFunction {
+            name: String::new(),
+            target_type: lambda_target_type(),
+            qualifier: None,
+            invocation_type: InvocationType::default(),
+            region: None,
+            endpoint_url: None,
+            role_arn: None,
+            sts_endpoint_url: None,
+            overrides: TargetFunctionOverrides::default(),
+            settings: HashMap::new()
+        }
+    }
+}
+
+impl From<String> for TargetFunction {
+    fn from(name: String) -> Self {
+        TargetFunction { name, ..TargetFunction::default() }
+    }
+}
+
+impl TargetFunction {
+
+    /// Reads the settings specific to this target `type`.
+    pub fn settings_as<T: DeserializeOwned>(&self) -> Result<T> {
+        let settings = serde_json::Value::Object(self.settings.clone().into_iter().collect());
+        serde_json::from_value(settings).map_err(|cause| KnownHandledErrors::InvalidConfiguration(
+            format!("invalid settings for target {}: {}", &self.name, cause)))
+    }
+
+    /// Identifies this target within its subscription. Targets pointing to different
+    /// qualifiers of the same function are consumed by different consumer groups.
+    /// As it's part of group (instance) ids, it only holds `[A-Za-z0-9._-]` characters:
+    /// the short function name and its qualifier, joined by `-` (e.g. `user_index-live`).
+    pub fn id(&self) -> String {
+        let name = match self.name.split_once(":function:") {
+            Some((_, name)) => name,
+            None => &self.name
+        };
+        let id = match &self.qualifier {
+            Some(qualifier) => format!("{}-{}", name, qualifier),
+            None => name.to_string()
+        };
+        id.chars()
+            .map(|char| if char.is_ascii_alphanumeric() || matches!(char, '.' | '_' | '-') { char } else { '-' })
+            .collect()
+    }
+}
+
+impl fmt::Display for InvocationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// The envelope records are wrapped into before being sent to the target functions.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    /// A JSON array of records, with their keys and values as strings.
+    #[default]
+    Array,
+    /// The event sent by AWS's native self-managed Kafka event source.
+    SelfManagedKafka,
+    /// A CloudEvents batch (`application/cloudevents-batch+json`).
+    CloudEvents
+}
+
+/// How the buffered records are handed to target functions.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DispatchMode {
+    /// Sends all buffered records within a single invocation.
+    #[default]
+    Batch,
+    /// Invokes the function once per record, up to `max_concurrency` invocations at a time.
+    /// Records are sent as a single element payload, or on their own if `unwrap_payload` is set.
+    /// The batch is only committed once every record has been successfully consumed.
+    PerRecord {
+        #[serde(default = "max_concurrency")]
+        max_concurrency: usize,
+        #[serde(default)]
+        unwrap_payload: bool
+    }
+}
+
+fn max_concurrency() -> usize { 10 }
+
+/// How target functions are mapped into consumer groups.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConsumerGroupMode {
+    /// Each target function has its own consumer group, reading the topic on its own.
+    #[default]
+    PerFunction,
+    /// A single consumer group reads the topic once and hands every batch to all
+    /// target functions. Per-function overrides only affect their `dispatch_mode`.
+    Shared {
+        #[serde(default)]
+        commit_policy: CommitPolicy
+    }
+}
+
+/// When a batch handed to many target functions is considered consumed.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitPolicy {
+    /// Every target function has consumed the batch.
+    #[default]
+    AllSucceeded,
+    /// At least one target function has consumed the batch.
+    AnySucceeded
+}
+
+/// Defines what should happen with records that, alone, are bigger
+/// than `topic_max_buffer_bytes` and therefore can't be delivered.
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OversizedRecordPolicy {
+    /// Sends the record without its value, along with its size and origin.
+    #[default]
+    MetadataOnly,
+    /// Writes the value into `directory` and sends a reference to it instead.
+    Offload { directory: String },
+    /// Publishes the record into a dead-letter topic.
+    DeadLetter { topic: String },
+    /// Discards the record. Skipped records are counted by the `oversized_records` metric.
+    Skip
+}
+
+/// Conditions a record must meet, all of them, to match a filter. Headers and
+/// JSON paths (e.g. `$.user.roles[0]`) into the value must be equal to one of
+/// their accepted values, and the key must match `key_pattern`, a regular expression.
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RecordFilterConfig {
+    #[serde(default)]
+    pub headers: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub key_pattern: Option<String>,
+    #[serde(default)]
+    pub value: HashMap<String, Vec<serde_json::Value>>
+}
+
+/// Delivers records meeting the `conditions` to the `target` function alone.
+/// Routes without conditions match every record.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "RouteDefinition")]
+pub struct RouteConfig {
+    pub target: String,
+    pub conditions: RecordFilterConfig
+}
+
+/// Routes declare their conditions along with their target. Unlike flattened
+/// fields, these reject unknown (e.g. misspelled) conditions.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteDefinition {
+    target: String,
+    #[serde(default)]
+    headers: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    key_pattern: Option<String>,
+    #[serde(default)]
+    value: HashMap<String, Vec<serde_json::Value>>
+}
+
+impl From<RouteDefinition> for RouteConfig {
+    fn from(definition: RouteDefinition) -> Self {
+        RouteConfig {
+            target: definition.target,
+            conditions: RecordFilterConfig {
+                headers: definition.headers,
+                key_pattern: definition.key_pattern,
+                value: definition.value
+            }
+        }
+    }
 }
 
 fn min_number_of_consumers() -> u32 { 1 }
+fn group_instance_id_template() -> String { "{topic}-{function}-{consumer}".to_string() }
 fn max_buffer_size() -> usize { 100 }
+/// AWS Lambda synchronous invocation payload limit (6 MB).
+fn max_buffer_bytes() -> usize { 6 * 1024 * 1024 }
 fn max_buffer_await_time_ms() -> u64 { 1000 }
+fn throttling_backoff_time_ms() -> u64 { 1000 }
+fn max_throttling_backoff_time_ms() -> u64 { 60000 }
+fn wasm_transform_fuel() -> u64 { 10_000_000 }
+
+/// Defines how subscribers are restarted when they fail. Once a subscriber
+/// fails more than `max_restarts` times within `restart_window` milliseconds,
+/// Malka gives up and exits, so its orchestrator can take over.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct RestartPolicy {
+    #[serde(default = "max_restarts")]
+    pub max_restarts: u32,
+    #[serde(default = "restart_window_ms")]
+    pub restart_window: u64,
+    #[serde(default = "restart_backoff_time_ms")]
+    pub backoff_time: u64,
+    #[serde(default = "max_restart_backoff_time_ms")]
+    pub max_backoff_time: u64
+}
+
+fn max_restarts() -> u32 { 5 }
+fn restart_window_ms() -> u64 { 300000 }
+fn restart_backoff_time_ms() -> u64 { 1000 }
+fn max_restart_backoff_time_ms() -> u64 { 60000 }
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: max_restarts(),
+            restart_window: restart_window_ms(),
+            backoff_time: restart_backoff_time_ms(),
+            max_backoff_time: max_restart_backoff_time_ms()
+        }
+    }
+}
 
 impl SubscriptionConfig {
 
+    /// Checks the settings that can't be checked on their own, failing fast
+    /// instead of letting subscribers fail over and over again.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(target_function) = self.target_functions.iter().find(|target| target.id() == SHARED_CONSUMER_GROUP_NAME) {
+            return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                "target function {} of {} can't be identified as '{}', as it's reserved for shared consumer groups",
+                &target_function.name, &self.topic_name, SHARED_CONSUMER_GROUP_NAME)))
+        }
+        if self.consumer_group_mode == ConsumerGroupMode::PerFunction && self.group_id.is_some() && self.target_functions.len() > 1 {
+            // Target functions would join the same consumer group, each of them missing the records read by the others.
+            return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                "target functions of {} have their own consumer groups, so their group_id should be set through their overrides",
+                &self.topic_name)))
+        }
+        if self.consumer_group_mode == ConsumerGroupMode::PerFunction && !self.routes.is_empty() {
+            return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                "routes of {} require a shared consumer group mode", &self.topic_name)))
+        }
+        let is_dry_run = |target: &TargetFunction| target.invocation_type == InvocationType::DryRun;
+        if self.consumer_group_mode != ConsumerGroupMode::PerFunction
+            && self.target_functions.iter().any(is_dry_run) && !self.target_functions.iter().all(is_dry_run) {
+            // Records of the shared consumer group would either be committed without being validated, or never be committed.
+            return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                "DryRun target functions of {} can't share a consumer group with other target functions", &self.topic_name)))
+        }
+        if self.consumer_group_mode == ConsumerGroupMode::PerFunction {
+            let mut ids = HashSet::new();
+            for target_function in &self.target_functions {
+                if !ids.insert(target_function.id()) {
+                    return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                        "target functions of {} must have distinct ids, but {} is repeated",
+                        &self.topic_name, target_function.id())))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// This configuration, with the settings overridden by `target_function` applied.
+    pub fn overridden_by(&self, target_function: &TargetFunction) -> SubscriptionConfig {
+        let overrides = &target_function.overrides;
+        let mut config = self.clone();
+        if let Some(number_of_consumers) = overrides.topic_number_of_consumers {
+            config.topic_number_of_consumers = number_of_consumers;
+        }
+        if let Some(max_buffer_size) = overrides.topic_max_buffer_size {
+            config.topic_max_buffer_size = max_buffer_size;
+        }
+        if let Some(max_buffer_bytes) = overrides.topic_max_buffer_bytes {
+            config.topic_max_buffer_bytes = max_buffer_bytes;
+        }
+        if let Some(max_buffer_await_time) = overrides.topic_max_buffer_await_time {
+            config.topic_max_buffer_await_time = max_buffer_await_time;
+        }
+        if overrides.dead_letter_topic.is_some() {
+            config.dead_letter_topic = overrides.dead_letter_topic.clone();
+        }
+        if let Some(dispatch_mode) = overrides.dispatch_mode {
+            config.dispatch_mode = dispatch_mode;
+        }
+        if overrides.group_id.is_some() {
+            config.group_id = overrides.group_id.clone();
+        }
+        config
+    }
+
     /// Creates a rdkafka::ClientConfig object based on this configuration.
-    pub fn as_client_config_for(&self, target_function: &str, parallel_consumer_id: u32) -> ClientConfig {
-        let group_id = format!("{}-{}", &self.topic_name, &target_function);
+    pub fn as_client_config_for(&self, target_function: &str, parallel_consumer_id: u32) -> Result<ClientConfig> {
+        let group_id = self.group_id.clone()
+            .unwrap_or_else(|| format!("{}-{}", &self.topic_name, &target_function));
         info!("Consumer Group ID: {}", &group_id);
 
-        let group_instance_id = format!("{}-{}-{}", &self.topic_name, target_function, parallel_consumer_id);
-        info!("Consumer Group ID: {}", &group_instance_id);
+        let group_instance_id = self.render_group_instance_id(target_function, &group_id, parallel_consumer_id)?;
+        info!("Consumer Group Instance ID: {}", &group_instance_id);
 
         let mut config = SubscriptionConfig::create_default_kafka_config();
+        config.set("enable.auto.commit", "false");
         config.set("group.id", group_id);
         config.set("group.instance.id", group_instance_id);
-        config.set("fetch.wait.max.ms", &self.topic_max_buffer_await_time.to_string());
-        config.set("batch.num.messages", &self.topic_max_buffer_size.to_string());
+        config.set("fetch.wait.max.ms", self.topic_max_buffer_await_time.to_string());
+        config.set("batch.num.messages", self.topic_max_buffer_size.to_string());
+
+        self.apply_consumer_configuration(&mut config);
+        Ok(config)
+    }
+
+    /// Renders `group_instance_id_template`, replacing the following placeholders:
+    /// `{topic}`, `{function}`, `{group_id}`, `{consumer}` (the parallel consumer id),
+    /// `{hostname}` and `{env:NAME}` (the value of the `NAME` environment variable).
+    /// Templates must include `{consumer}` when there's more than one consumer,
+    /// otherwise parallel consumers would collide on identical instance ids.
+    fn render_group_instance_id(&self, target_function: &str, group_id: &str, parallel_consumer_id: u32) -> Result<String> {
+        let template = &self.group_instance_id_template;
+        if self.topic_number_of_consumers > 1 && !template.contains("{consumer}") {
+            return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                "group instance id template '{}' must include {{consumer}}", template)))
+        }
+
+        let mut rendered = String::new();
+        let mut remaining = template.as_str();
+        while let Some(start) = remaining.find('{') {
+            let end = remaining[start..].find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| KnownHandledErrors::InvalidConfiguration(format!(
+                    "unterminated placeholder in group instance id template '{}'", template)))?;
+
+            rendered.push_str(&remaining[..start]);
+            let value = match &remaining[start + 1..end] {
+                "topic" => self.topic_name.clone(),
+                "function" => target_function.to_string(),
+                "group_id" => group_id.to_string(),
+                "consumer" => parallel_consumer_id.to_string(),
+                "hostname" => hostname::get()?.to_string_lossy().to_string(),
+                placeholder => match placeholder.strip_prefix("env:") {
+                    Some(name) => env::var(name)
+                        .map_err(|_| KnownHandledErrors::InvalidEnvironmentVariable(name.to_string()))?,
+                    None => return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                        "unknown placeholder {{{}}} in group instance id template '{}'", placeholder, template)))
+                }
+            };
+            rendered.push_str(&value);
+            remaining = &remaining[end + 1..];
+        }
+        rendered.push_str(remaining);
+        Ok(rendered)
+    }
 
+    /// Creates a rdkafka::ClientConfig object for producers publishing on behalf
+    /// of this subscription (e.g. into dead-letter topics).
+    pub fn as_producer_config(&self) -> ClientConfig {
+        let mut config = SubscriptionConfig::create_default_kafka_config();
+        self.apply_consumer_configuration(&mut config);
+        config
+    }
+
+    fn apply_consumer_configuration(&self, config: &mut ClientConfig) {
         if let Some(extra_config) = &self.consumer_configuration {
             for (key, value) in extra_config {
                 config.set(key, value);
             }
         }
-
-        config
     }
 
     fn create_default_kafka_config() -> ClientConfig {
@@ -61,7 +658,6 @@ impl SubscriptionConfig {
 
         cfg.set("bootstrap.servers", kafka_brokers)
             .set("security.protocol", security_protocol)
-            .set("enable.auto.commit", "false")
             .set_log_level(RDKafkaLogLevel::Debug);
 
         cfg
@@ -70,7 +666,9 @@ impl SubscriptionConfig {
 
 #[cfg(test)]
 mod test {
-    use crate::conf::SubscriptionConfig;
+    use std::collections::HashMap;
+
+    use crate::conf::{CommitPolicy, ConsumerGroupMode, DispatchMode, InvocationType, OversizedRecordPolicy, PayloadFormat, RestartPolicy, SubscriptionConfig, TargetFunction, TargetFunctionOverrides, WebhookAuth, WebhookConfig};
 
     #[test]
     fn should_serialize_subscription_config_correctly() {
@@ -86,9 +684,24 @@ mod test {
             topic_name: "user.delete".to_string(),
             topic_number_of_consumers: 1,
             topic_max_buffer_await_time: 1000,
+            topic_throttling_backoff_time: 1000,
+            topic_max_throttling_backoff_time: 60000,
             topic_max_buffer_size: 100,
+            topic_max_buffer_bytes: 6 * 1024 * 1024,
+            oversized_record_policy: OversizedRecordPolicy::MetadataOnly,
+            dead_letter_topic: None,
+            payload_format: PayloadFormat::Array,
+            dispatch_mode: DispatchMode::Batch,
+            consumer_group_mode: ConsumerGroupMode::PerFunction,
+            restart_policy: RestartPolicy::default(),
+            filters: Vec::new(),
+            routes: Vec::new(),
+            wasm_transform: None,
+            wasm_transform_fuel: 10_000_000,
             consumer_configuration: None,
-            target_functions: vec!("user_deleted".to_string())
+            group_id: None,
+            group_instance_id_template: "{topic}-{function}-{consumer}".to_string(),
+            target_functions: vec!(TargetFunction::from("user_deleted".to_string()))
         };
         assert_eq!(expected_first_cfg, configs[0]);
 
@@ -96,10 +709,224 @@ mod test {
             topic_name: "user.update".to_string(),
             topic_number_of_consumers: 2,
             topic_max_buffer_await_time: 1000,
+            topic_throttling_backoff_time: 1000,
+            topic_max_throttling_backoff_time: 60000,
             topic_max_buffer_size: 100,
+            topic_max_buffer_bytes: 6 * 1024 * 1024,
+            oversized_record_policy: OversizedRecordPolicy::MetadataOnly,
+            dead_letter_topic: None,
+            payload_format: PayloadFormat::Array,
+            dispatch_mode: DispatchMode::Batch,
+            consumer_group_mode: ConsumerGroupMode::PerFunction,
+            restart_policy: RestartPolicy::default(),
+            filters: Vec::new(),
+            routes: Vec::new(),
+            wasm_transform: None,
+            wasm_transform_fuel: 10_000_000,
             consumer_configuration: None,
-            target_functions: vec!("user_updated".to_string())
+            group_id: None,
+            group_instance_id_template: "{topic}-{function}-{consumer}".to_string(),
+            target_functions: vec!(TargetFunction::from("user_updated".to_string()))
         };
         assert_eq!(expected_second_cfg, configs[1]);
     }
+
+    #[test]
+    fn should_accept_target_functions_defined_either_by_name_or_object() {
+        let json = r#"{
+         "topic_name": "user.delete",
+         "target_functions": [
+           "user_deleted",
+           { "name": "user_audit", "invocation_type": "Event" },
+           { "name": "arn:aws:lambda:us-east-1:123456789012:function:user_index", "qualifier": "live",
+             "overrides": { "topic_max_buffer_size": 10 } }
+         ]
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let expected_functions = vec!(
+            TargetFunction::from("user_deleted".to_string()),
+            TargetFunction { name: "user_audit".to_string(), invocation_type: InvocationType::Event, ..TargetFunction::default() },
+            TargetFunction {
+                name: "arn:aws:lambda:us-east-1:123456789012:function:user_index".to_string(),
+                qualifier: Some("live".to_string()),
+                overrides: TargetFunctionOverrides { topic_max_buffer_size: Some(10), ..TargetFunctionOverrides::default() },
+                ..TargetFunction::default()
+            }
+        );
+        assert_eq!(expected_functions, config.target_functions);
+        assert_eq!("user_index-live", config.target_functions[2].id());
+
+    }
+
+    #[test]
+    fn should_only_accept_dry_run_target_functions_with_their_own_consumer_groups() {
+        let json = r#"{
+         "topic_name": "user.delete",
+         "target_functions": ["user_deleted", { "name": "user_audit", "invocation_type": "DryRun" }]
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(InvocationType::DryRun, config.target_functions[1].invocation_type);
+        assert!(config.validate().is_ok());
+
+        let config = SubscriptionConfig { consumer_group_mode: ConsumerGroupMode::Shared { commit_policy: CommitPolicy::AllSucceeded }, ..config };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn should_reject_target_functions_with_conflicting_ids() {
+        let json = r#"{
+         "topic_name": "user.delete",
+         "target_functions": ["user_index", "arn:aws:lambda:us-east-1:123456789012:function:user_index"]
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        assert!(config.validate().is_err());
+
+        let config = SubscriptionConfig { target_functions: vec!(TargetFunction::from("shared".to_string())), ..config };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn should_apply_target_function_overrides() {
+        let json = r#"{
+         "topic_name": "user.delete", "topic_max_buffer_size": 50,
+         "target_functions": [{ "name": "user_deleted", "overrides": { "topic_max_buffer_size": 10, "dead_letter_topic": "user.delete.dlt" } }]
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let overridden = config.overridden_by(&config.target_functions[0]);
+        assert_eq!(10, overridden.topic_max_buffer_size);
+        assert_eq!(Some("user.delete.dlt".to_string()), overridden.dead_letter_topic);
+        assert_eq!(config.topic_max_buffer_await_time, overridden.topic_max_buffer_await_time);
+    }
+
+    #[test]
+    fn should_use_explicit_group_id_and_instance_id_template() {
+        let json = r#"{
+         "topic_name": "user.delete", "topic_number_of_consumers": 2, "target_functions": ["user_deleted"],
+         "group_id": "legacy-user-deleted", "group_instance_id_template": "{group_id}-{function}-{consumer}"
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        assert!(config.validate().is_ok());
+        let client_config = config.as_client_config_for("user_deleted", 1).unwrap();
+        assert_eq!(Some("legacy-user-deleted"), client_config.get("group.id"));
+        assert_eq!(Some("legacy-user-deleted-user_deleted-1"), client_config.get("group.instance.id"));
+
+        let config = SubscriptionConfig { group_instance_id_template: "{group_id}-{env:MALKA_TEST_UNSET_TASK_ID}-{consumer}".to_string(), ..config };
+        assert!(config.as_client_config_for("user_deleted", 1).is_err());
+    }
+
+    #[test]
+    fn should_only_accept_a_subscription_group_id_when_targets_share_it() {
+        let json = r#"{
+         "topic_name": "user.delete", "target_functions": ["user_deleted", "user_audit"], "group_id": "legacy-user-deleted"
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        assert!(config.validate().is_err());
+
+        let config = SubscriptionConfig { consumer_group_mode: ConsumerGroupMode::Shared { commit_policy: CommitPolicy::AllSucceeded }, ..config };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn should_only_accept_routes_with_known_conditions_in_shared_consumer_groups() {
+        let json = r#"{
+         "topic_name": "user.delete", "target_functions": ["user_deleted", "user_audit"],
+         "routes": [{ "target": "user_audit", "key_pattern": "^admin-" }]
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(Some("^admin-"), config.routes[0].conditions.key_pattern.as_deref());
+        assert!(config.validate().is_err());
+
+        let config = SubscriptionConfig { consumer_group_mode: ConsumerGroupMode::Shared { commit_policy: CommitPolicy::AllSucceeded }, ..config };
+        assert!(config.validate().is_ok());
+
+        let misspelled = json.replace("key_pattern", "key_patern");
+        assert!(serde_json::from_str::<SubscriptionConfig>(&misspelled).is_err());
+    }
+
+    #[test]
+    fn should_reject_instance_id_templates_colliding_among_parallel_consumers() {
+        let json = r#"{
+         "topic_name": "user.delete", "topic_number_of_consumers": 2, "target_functions": ["user_deleted"],
+         "group_instance_id_template": "{topic}-{hostname}"
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        assert!(config.as_client_config_for("user_deleted", 0).is_err());
+    }
+
+    #[test]
+    fn should_deserialize_webhook_targets() {
+        let json = r#"{
+         "topic_name": "user.delete",
+         "target_functions": [{
+           "name": "user_deleted", "type": "webhook",
+           "url": "https://example.com/users", "auth": { "type": "hmac", "secret": "s3cr3t" }
+         }]
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let expected_webhook = WebhookConfig {
+            url: "https://example.com/users".to_string(),
+            headers: HashMap::new(),
+            timeout: 30000,
+            auth: Some(WebhookAuth::Hmac { secret: "s3cr3t".to_string(), header: "x-malka-signature".to_string() })
+        };
+        assert_eq!("webhook", config.target_functions[0].target_type);
+        assert_eq!(expected_webhook, config.target_functions[0].settings_as::<WebhookConfig>().unwrap());
+    }
+
+    #[test]
+    fn should_deserialize_shared_consumer_group_mode() {
+        let json = r#"{
+         "topic_name": "user.delete", "target_functions": ["user_deleted", "user_audit"],
+         "consumer_group_mode": { "type": "shared", "commit_policy": "any_succeeded" }
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let expected_mode = ConsumerGroupMode::Shared { commit_policy: CommitPolicy::AnySucceeded };
+        assert_eq!(expected_mode, config.consumer_group_mode);
+    }
+
+    #[test]
+    fn should_deserialize_per_record_dispatch_mode_with_defaults() {
+        let json = r#"{
+         "topic_name": "user.delete", "target_functions": ["user_deleted"],
+         "dispatch_mode": { "type": "per_record", "unwrap_payload": true }
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let expected_mode = DispatchMode::PerRecord { max_concurrency: 10, unwrap_payload: true };
+        assert_eq!(expected_mode, config.dispatch_mode);
+    }
+
+    #[test]
+    fn should_deserialize_oversized_record_policy() {
+        let json = r#"{
+         "topic_name": "user.delete", "target_functions": ["user_deleted"],
+         "oversized_record_policy": { "type": "dead_letter", "topic": "user.delete.dlt" }
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let expected_policy = OversizedRecordPolicy::DeadLetter { topic: "user.delete.dlt".to_string() };
+        assert_eq!(expected_policy, config.oversized_record_policy);
+    }
+
+    #[test]
+    fn should_fill_missing_restart_policy_fields_with_defaults() {
+        let json = r#"{
+         "topic_name": "user.delete", "target_functions": ["user_deleted"],
+         "restart_policy": { "max_restarts": 10 }
+        }"#;
+
+        let config: SubscriptionConfig = serde_json::from_str(json).unwrap();
+        let expected_policy = RestartPolicy { max_restarts: 10, ..RestartPolicy::default() };
+        assert_eq!(expected_policy, config.restart_policy);
+    }
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
 use thiserror::Error;
 use rdkafka::error::KafkaError;
 
+use crate::kafka::error::KafkaConsumerError;
+
 pub type Result<T> = std::result::Result<T, KnownHandledErrors>;
 
 #[derive(Error, Debug)]
@@ -9,6 +11,39 @@ pub enum KnownHandledErrors {
     #[error(transparent)]
     Kafka(#[from] KafkaError),
 
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+
+    #[error(transparent)]
+    InvalidAddress(#[from] std::net::AddrParseError),
+
+    #[error(transparent)]
+    InvalidRegion(#[from] rusoto_core::region::ParseRegionError),
+
+    #[error(transparent)]
+    AwsCredentials(#[from] rusoto_core::credential::CredentialsError),
+
+    #[error(transparent)]
+    Tls(#[from] rusoto_core::request::TlsError),
+
+    #[error(transparent)]
+    Grpc(#[from] tonic::transport::Error),
+
+    #[error("Subscriber halted: {0}")]
+    SubscriberHalted(KafkaConsumerError),
+
+    #[error("No dead-letter topic configured")]
+    DeadLetterTopicNotConfigured,
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(String),
+
+    #[error("Invalid value for environment variable {0}")]
+    InvalidEnvironmentVariable(String),
+
     #[error("Expected one or more 'file names' as parameters")]
     InvalidParameters
 }
\ No newline at end of file
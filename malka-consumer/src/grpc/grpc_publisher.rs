@@ -0,0 +1,296 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{error, warn};
+use prost::Message;
+use tonic::{Code, Request, Status};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+
+use crate::aws::client_context::ConsumerIdentity;
+use crate::conf::GrpcConfig;
+use crate::error::{KnownHandledErrors, Result};
+use crate::grpc::proto::{ConsumeRequest, ConsumeResponse, Header, Outcome, OversizedValue, Record};
+use crate::grpc::proto::record_consumer_client::RecordConsumerClient;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::PayloadEncoder;
+
+/// Tag of `ConsumeRequest.records`.
+const RECORDS_TAG: u32 = 3;
+
+/// The default limit of messages decoded by gRPC servers.
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// A `KafkaConsumerListener` implementation that calls the `malka.v1.RecordConsumer/Consume`
+/// RPC. Records are considered consumed when the call succeeds and no record status says otherwise.
+/// As records are sent as protobuf messages, payloads are sized the same way.
+pub struct GrpcKafkaConsumerListener {
+    consumer: ConsumerIdentity,
+    endpoint: String,
+    metadata: MetadataMap,
+    timeout: Duration,
+    channel: Channel
+}
+
+impl GrpcKafkaConsumerListener {
+
+    /// Creates the listener. Connections are only established once records are consumed.
+    pub fn create(config: GrpcConfig, consumer: ConsumerIdentity) -> Result<Self> {
+        let timeout = Duration::from_millis(config.timeout);
+        let mut endpoint = Endpoint::from_shared(config.endpoint.clone())?.timeout(timeout);
+
+        if config.tls.is_some() || config.endpoint.starts_with("https://") {
+            let tls = config.tls.clone().unwrap_or_default();
+            let mut tls_config = ClientTlsConfig::new();
+            if let Some(ca_certificate) = &tls.ca_certificate {
+                tls_config = tls_config.ca_certificate(Certificate::from_pem(std::fs::read(ca_certificate)?));
+            }
+            if let Some(domain_name) = tls.domain_name {
+                tls_config = tls_config.domain_name(domain_name);
+            }
+            endpoint = endpoint.tls_config(tls_config)?;
+        }
+
+        let mut metadata = MetadataMap::new();
+        for (key, value) in &config.metadata {
+            let invalid = || KnownHandledErrors::InvalidConfiguration(
+                format!("invalid gRPC metadata {} of {}", key, &config.endpoint));
+            let key = MetadataKey::from_bytes(key.as_bytes()).map_err(|_| invalid())?;
+            let value = MetadataValue::try_from(value.as_str()).map_err(|_| invalid())?;
+            metadata.insert(key, value);
+        }
+
+        Ok(GrpcKafkaConsumerListener {
+            consumer, metadata, timeout,
+            channel: endpoint.connect_lazy(),
+            endpoint: config.endpoint
+        })
+    }
+
+    fn create_message(&self, records: &[InFlightRecord]) -> ConsumeRequest {
+        ConsumeRequest {
+            group_id: self.consumer.group_id.clone(),
+            group_instance_id: self.consumer.group_instance_id.clone(),
+            records: records.iter().map(to_proto).collect()
+        }
+    }
+
+    fn create_request(&self, records: &[InFlightRecord]) -> Request<ConsumeRequest> {
+        let mut request = Request::new(self.create_message(records));
+        request.metadata_mut().clone_from(&self.metadata);
+        request.set_timeout(self.timeout);
+        request
+    }
+
+    async fn call(&self, request: Request<ConsumeRequest>) -> std::result::Result<ConsumeResponse, Status> {
+        let response = RecordConsumerClient::new(self.channel.clone()).consume(request).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Failures only affect the records their statuses name. Statuses of records
+    /// that aren't part of the batch are ignored.
+    fn classify_response(&self, records: &[InFlightRecord], response: ConsumeResponse) -> KafkaConsumerResult {
+        let failures = response.statuses.into_iter()
+            .filter_map(|status| {
+                let record = records.iter()
+                    .find(|record| record.partition == status.partition && record.offset == status.offset);
+                let record = match record {
+                    Some(record) => record,
+                    None => {
+                        warn!("{} reported the status of record {}@{}, which isn't part of the batch. Ignoring it.",
+                              &self.endpoint, status.partition, status.offset);
+                        return None
+                    }
+                };
+
+                let cause = || FailureCause::new(format!(
+                    "{} failed to consume record {}-{}@{}", &self.endpoint, &record.topic, record.partition, record.offset));
+                let failure = match Outcome::from_i32(status.outcome) {
+                    Some(Outcome::Succeeded) => return None,
+                    Some(Outcome::Throttled) => KafkaConsumerError::Throttled(cause()),
+                    Some(Outcome::InvalidPayload) => KafkaConsumerError::InvalidPayload(cause()),
+                    Some(Outcome::Retry) | None => KafkaConsumerError::Network(cause())
+                };
+                Some(failure.affecting(vec!(record.coordinates())))
+            });
+
+        match KafkaConsumerError::prevailing(failures) {
+            Some(cause) => KafkaConsumerResult::Failed(cause),
+            None => KafkaConsumerResult::Succeeded
+        }
+    }
+
+    fn classify_failure(&self, status: Status) -> KafkaConsumerError {
+        let classify: fn(FailureCause) -> KafkaConsumerError = match status.code() {
+            Code::ResourceExhausted => KafkaConsumerError::Throttled,
+            Code::InvalidArgument | Code::OutOfRange => KafkaConsumerError::InvalidPayload,
+            Code::NotFound | Code::Unimplemented => KafkaConsumerError::NotFound,
+            Code::PermissionDenied | Code::Unauthenticated => KafkaConsumerError::PermissionDenied,
+            _ => KafkaConsumerError::Network
+        };
+        classify(FailureCause::with_source(format!("Failed to call {}", &self.endpoint), status))
+    }
+}
+
+fn to_proto(record: &InFlightRecord) -> Record {
+    Record {
+        topic: record.topic.clone(),
+        partition: record.partition,
+        offset: record.offset,
+        key: record.key.clone(),
+        value: record.value.clone(),
+        timestamp: record.timestamp.to_millis(),
+        headers: record.headers.iter()
+            .map(|(key, value)| Header { key: key.clone(), value: value.clone() })
+            .collect(),
+        oversized_value: record.oversized_value.as_ref().map(|oversized_value| OversizedValue {
+            size: oversized_value.size as u64,
+            key_size: oversized_value.key_size.map(|key_size| key_size as u64),
+            location: oversized_value.location.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl KafkaConsumerListener for GrpcKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        match self.call(self.create_request(records)).await {
+            Ok(response) => self.classify_response(records, response),
+            Err(status) => {
+                error!("Failed to call {}: {}", &self.endpoint, status);
+                KafkaConsumerResult::Failed(self.classify_failure(status))
+            }
+        }
+    }
+
+    fn max_payload_size(&self) -> Option<usize> {
+        Some(MAX_MESSAGE_SIZE)
+    }
+
+    fn empty_payload_size(&self, _encoder: &PayloadEncoder) -> usize {
+        self.create_message(&[]).encoded_len()
+    }
+
+    fn payload_size_increment(&self, _encoder: &PayloadEncoder, record: &InFlightRecord, _records_in_payload: usize) -> usize {
+        prost::encoding::message::encoded_len(RECORDS_TAG, &to_proto(record))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use prost::Message;
+    use tonic::{Code, Status};
+
+    use crate::aws::client_context::ConsumerIdentity;
+    use crate::conf::{GrpcConfig, PayloadFormat};
+    use crate::grpc::proto;
+    use crate::grpc::proto::{ConsumeResponse, Outcome, RecordStatus};
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult, OversizedValue};
+    use crate::kafka::error::KafkaConsumerError;
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::GrpcKafkaConsumerListener;
+
+    fn listener(endpoint: &str) -> GrpcKafkaConsumerListener {
+        let config = GrpcConfig {
+            endpoint: endpoint.to_string(),
+            metadata: HashMap::from([("x-source".to_string(), "malka".to_string())]),
+            timeout: 500,
+            tls: None
+        };
+        let consumer = ConsumerIdentity {
+            group_id: "user.delete-user_deleted".to_string(),
+            group_instance_id: "user.delete-user_deleted-0".to_string()
+        };
+        GrpcKafkaConsumerListener::create(config, consumer).unwrap()
+    }
+
+    fn status(offset: i64, outcome: Outcome) -> RecordStatus {
+        RecordStatus { partition: 0, offset, outcome: outcome as i32 }
+    }
+
+    fn records() -> Vec<InFlightRecord> {
+        (0..3).map(|offset| InFlightRecord { topic: "user.delete".to_string(), offset, ..InFlightRecord::create(None, None) }).collect()
+    }
+
+    #[tokio::test]
+    async fn should_fail_with_the_prevailing_record_status() {
+        let listener = listener("http://127.0.0.1:50051");
+
+        let response = ConsumeResponse { statuses: vec!(status(1, Outcome::Succeeded)) };
+        assert_eq!(KafkaConsumerResult::Succeeded, listener.classify_response(&records(), response));
+
+        let response = ConsumeResponse {
+            statuses: vec!(status(1, Outcome::InvalidPayload), status(2, Outcome::Throttled))
+        };
+        let result = listener.classify_response(&records(), response);
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Throttled(_))));
+    }
+
+    #[tokio::test]
+    async fn should_only_dead_letter_the_records_with_failed_statuses() {
+        let listener = listener("http://127.0.0.1:50051");
+
+        let response = ConsumeResponse {
+            statuses: vec!(status(0, Outcome::Succeeded), status(1, Outcome::InvalidPayload), status(7, Outcome::Retry))
+        };
+        match listener.classify_response(&records(), response) {
+            KafkaConsumerResult::Failed(failure @ KafkaConsumerError::InvalidPayload(_)) =>
+                assert_eq!(Some(&[("user.delete".to_string(), 0, 1)][..]), failure.cause().records()),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+
+    #[tokio::test]
+    async fn should_map_status_codes_into_failures() {
+        let listener = listener("http://127.0.0.1:50051");
+
+        assert!(listener.classify_failure(Status::new(Code::Unavailable, "")).is_retriable());
+        assert!(listener.classify_failure(Status::new(Code::DeadlineExceeded, "")).is_retriable());
+        assert!(matches!(listener.classify_failure(Status::new(Code::ResourceExhausted, "")),
+                         KafkaConsumerError::Throttled(_)));
+        assert!(matches!(listener.classify_failure(Status::new(Code::InvalidArgument, "")),
+                         KafkaConsumerError::InvalidPayload(_)));
+        assert!(matches!(listener.classify_failure(Status::new(Code::Unauthenticated, "")),
+                         KafkaConsumerError::PermissionDenied(_)));
+    }
+
+    #[tokio::test]
+    async fn should_size_payloads_as_protobuf_messages() {
+        let listener = listener("http://127.0.0.1:50051");
+        let encoder = PayloadEncoder::create(PayloadFormat::Array, String::new());
+        let records = vec!(
+            InFlightRecord { topic: "user.delete".to_string(), headers: vec!(("source".to_string(), vec!(0xff))), ..InFlightRecord::create(Some(b"user-1"), Some(&[0xff; 300])) },
+            InFlightRecord { topic: "user.delete".to_string(), offset: 1, oversized_value: Some(oversized_value()), ..InFlightRecord::create(None, None) }
+        );
+
+        let mut measured_size = listener.empty_payload_size(&encoder);
+        for (records_in_payload, record) in records.iter().enumerate() {
+            measured_size += listener.payload_size_increment(&encoder, record, records_in_payload);
+        }
+        let message = listener.create_message(&records);
+        assert_eq!(message.encoded_len(), measured_size);
+
+        let expected = proto::OversizedValue { size: 7340032, key_size: Some(6), location: Some("s3://records/user.delete-0-1".to_string()) };
+        assert_eq!(None, message.records[0].oversized_value);
+        assert_eq!(Some(expected), message.records[1].oversized_value);
+    }
+
+    fn oversized_value() -> OversizedValue {
+        OversizedValue {
+            size: 7340032, key_size: Some(6), topic: "user.delete".to_string(), partition: 0, offset: 1,
+            location: Some("s3://records/user.delete-0-1".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn should_retry_when_the_service_is_unreachable() {
+        let result = listener("http://127.0.0.1:1").consume(&[InFlightRecord::create(None, None)]).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(failure) if failure.is_retriable()));
+    }
+}
@@ -0,0 +1,2 @@
+pub mod grpc_publisher;
+mod proto;
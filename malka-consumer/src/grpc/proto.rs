@@ -0,0 +1,3 @@
+//! Messages and client of `proto/malka.proto`, generated by `build.rs`.
+
+tonic::include_proto!("malka.v1");
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use log::info;
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::metrics;
+
+/// Path metrics are served on.
+const METRICS_PATH: &str = "/metrics";
+
+/// The current state of a subscriber.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SubscriberHealth {
+    Running,
+    /// The subscriber failed and is about to be recreated.
+    Recovering { failures: u32, last_failure: String },
+    Stopped
+}
+
+/// Keeps track of the health of every subscriber, identified by its group instance id.
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    subscribers: Arc<Mutex<BTreeMap<String, SubscriberHealth>>>
+}
+
+/// A snapshot of the subscribers health. Malka is considered
+/// healthy while none of its subscribers is recovering from failures.
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub subscribers: BTreeMap<String, SubscriberHealth>
+}
+
+impl HealthRegistry {
+
+    pub fn update(&self, subscriber_id: &str, health: SubscriberHealth) {
+        self.subscribers.lock().unwrap()
+            .insert(subscriber_id.to_string(), health);
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let subscribers = self.subscribers.lock().unwrap().clone();
+        let healthy = subscribers.values()
+            .all(|health| !matches!(health, SubscriberHealth::Recovering { .. }));
+        HealthReport { healthy, subscribers }
+    }
+}
+
+impl HealthReport {
+    fn into_response(self) -> Response<Body> {
+        let status = if self.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        let body = serde_json::to_string(&self).expect("Failed to serialize health report");
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .expect("Failed to create health report response")
+    }
+}
+
+/// Serves the metrics, in the Prometheus text format, to requests sent to `/metrics`.
+fn metrics_response() -> Response<Body> {
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(metrics::global().render()))
+        .expect("Failed to create metrics response")
+}
+
+fn respond(request: &Request<Body>, registry: &HealthRegistry) -> Response<Body> {
+    match request.uri().path() {
+        METRICS_PATH => metrics_response(),
+        _ => registry.report().into_response()
+    }
+}
+
+/// Serves the health report, as JSON, to any HTTP request sent to `address`, except
+/// for `/metrics`, which serves the metrics. Responds with `503 Service Unavailable`
+/// while Malka isn't healthy.
+pub async fn serve(address: SocketAddr, registry: HealthRegistry) -> Result<()> {
+    info!("Serving health reports and metrics on {}", &address);
+    let make_service = make_service_fn(move |_| {
+        let registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&request, &registry);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::try_bind(&address)?
+        .serve(make_service)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Request};
+
+    use crate::metrics;
+
+    use super::{HealthRegistry, SubscriberHealth, respond};
+
+    #[test]
+    fn should_be_unhealthy_while_a_subscriber_is_recovering() {
+        let registry = HealthRegistry::default();
+        registry.update("user.delete-user_deleted-0", SubscriberHealth::Running);
+        assert!(registry.report().healthy);
+
+        let recovering = SubscriberHealth::Recovering { failures: 1, last_failure: "Broker down".to_string() };
+        registry.update("user.delete-user_deleted-1", recovering);
+        assert!(!registry.report().healthy);
+
+        registry.update("user.delete-user_deleted-1", SubscriberHealth::Running);
+        assert!(registry.report().healthy);
+    }
+
+    #[tokio::test]
+    async fn should_serve_metrics_along_with_health_reports() {
+        metrics::global().increment("health_test_events", &[("topic", "user.delete")]);
+        let registry = HealthRegistry::default();
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let body = hyper::body::to_bytes(respond(&request, &registry).into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("# TYPE malka_health_test_events counter\nmalka_health_test_events{topic=\"user.delete\"} 1\n"));
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let body = hyper::body::to_bytes(respond(&request, &registry).into_body()).await.unwrap();
+        assert_eq!(r#"{"healthy":true,"subscribers":{}}"#, String::from_utf8(body.to_vec()).unwrap());
+    }
+}
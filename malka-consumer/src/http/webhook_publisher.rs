@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use log::error;
+use sha2::Sha256;
+
+use crate::conf::{WebhookAuth, WebhookConfig};
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::PayloadEncoder;
+
+/// A `KafkaConsumerListener` implementation that POSTs records to an HTTP endpoint.
+/// Records are considered consumed once the endpoint responds with `2xx`.
+pub struct WebhookKafkaConsumerListener {
+    config: WebhookConfig,
+    payload_encoder: PayloadEncoder,
+    http_client: Client<HttpsConnector<HttpConnector>>
+}
+
+impl WebhookKafkaConsumerListener {
+    pub fn create(config: WebhookConfig, payload_encoder: PayloadEncoder) -> Self {
+        WebhookKafkaConsumerListener {
+            config, payload_encoder,
+            http_client: Client::builder().build(HttpsConnector::new())
+        }
+    }
+
+    fn create_request(&self, payload: Vec<u8>) -> Result<Request<Body>, KafkaConsumerError> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.config.url)
+            .header("content-type", self.payload_encoder.content_type());
+
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        request = match &self.config.auth {
+            None => request,
+            Some(WebhookAuth::Bearer { token }) =>
+                request.header("authorization", format!("Bearer {}", token)),
+            Some(WebhookAuth::Basic { username, password }) => {
+                let credentials = base64::encode(format!("{}:{}", username, password));
+                request.header("authorization", format!("Basic {}", credentials))
+            },
+            Some(WebhookAuth::Hmac { secret, header }) =>
+                request.header(header.as_str(), sign(secret, &payload))
+        };
+
+        request.body(Body::from(payload))
+            .map_err(|cause| KafkaConsumerError::InvalidPayload(FailureCause::with_source(
+                format!("Failed to create request to {}", &self.config.url), cause)))
+    }
+
+    fn classify_response(&self, status: StatusCode) -> KafkaConsumerResult {
+        if status.is_success() {
+            return KafkaConsumerResult::Succeeded
+        }
+
+        let cause = FailureCause::with_source(
+            format!("Webhook {} responded with {}", &self.config.url, status),
+            UnexpectedStatus(status));
+        let failure = match status.as_u16() {
+            429 => KafkaConsumerError::Throttled(cause),
+            413 => KafkaConsumerError::PayloadTooLarge(cause),
+            404 => KafkaConsumerError::NotFound(cause),
+            401 | 403 => KafkaConsumerError::PermissionDenied(cause),
+            code if code >= 500 => KafkaConsumerError::Network(cause),
+            _ => KafkaConsumerError::InvalidPayload(cause)
+        };
+        KafkaConsumerResult::Failed(failure)
+    }
+}
+
+/// Signs `payload` with HMAC-SHA256, formatted as `sha256=<hex digest>`.
+fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// A response status that couldn't be handled as success.
+#[derive(Debug)]
+struct UnexpectedStatus(StatusCode);
+
+impl std::fmt::Display for UnexpectedStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unexpected status {}", self.0)
+    }
+}
+
+impl std::error::Error for UnexpectedStatus {}
+
+#[async_trait]
+impl KafkaConsumerListener for WebhookKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let request = match self.create_request(self.payload_encoder.encode(records)) {
+            Ok(request) => request,
+            Err(cause) => return KafkaConsumerResult::Failed(cause)
+        };
+
+        let timeout = Duration::from_millis(self.config.timeout);
+        match tokio::time::timeout(timeout, self.http_client.request(request)).await {
+            Ok(Ok(response)) => self.classify_response(response.status()),
+            Ok(Err(cause)) => {
+                error!("Failed to reach webhook {}: {}", &self.config.url, cause);
+                KafkaConsumerResult::Failed(KafkaConsumerError::Network(FailureCause::with_source(
+                    format!("Failed to reach webhook {}", &self.config.url), cause)))
+            },
+            Err(cause) => KafkaConsumerResult::Failed(KafkaConsumerError::Network(FailureCause::with_source(
+                format!("Webhook {} timed out after {:?}", &self.config.url, timeout), cause)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use hyper::service::{make_service_fn, service_fn};
+
+    use crate::conf::{PayloadFormat, WebhookAuth, WebhookConfig};
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+    use crate::kafka::error::KafkaConsumerError;
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::{sign, WebhookKafkaConsumerListener};
+
+    type ReceivedHeaders = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    /// Serves `status` to every request, keeping track of the received headers.
+    fn serve(status: StatusCode) -> (SocketAddr, ReceivedHeaders) {
+        let received: ReceivedHeaders = Arc::new(Mutex::new(Vec::new()));
+        let received_by_server = Arc::clone(&received);
+        let make_service = make_service_fn(move |_| {
+            let received = Arc::clone(&received_by_server);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let headers = request.headers().iter()
+                        .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+                        .collect();
+                    received.lock().unwrap().push(headers);
+                    async move { Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap()) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, received)
+    }
+
+    fn listener(address: SocketAddr, auth: Option<WebhookAuth>) -> WebhookKafkaConsumerListener {
+        let config = WebhookConfig {
+            url: format!("http://{}/records", address),
+            headers: HashMap::from([("x-source".to_string(), "malka".to_string())]),
+            timeout: 1000,
+            auth
+        };
+        WebhookKafkaConsumerListener::create(config, PayloadEncoder::create(PayloadFormat::Array, String::new()))
+    }
+
+    #[tokio::test]
+    async fn should_post_signed_records_to_the_webhook() {
+        let (address, received) = serve(StatusCode::NO_CONTENT);
+        let auth = WebhookAuth::Hmac { secret: "s3cr3t".to_string(), header: "x-malka-signature".to_string() };
+        let record = InFlightRecord::create(Some("k".as_bytes()), Some("v".as_bytes()));
+
+        let result = listener(address, Some(auth)).consume(&[record]).await;
+        assert_eq!(KafkaConsumerResult::Succeeded, result);
+
+        let headers = &received.lock().unwrap()[0];
+        assert_eq!("malka", headers["x-source"]);
+        assert_eq!("application/json", headers["content-type"]);
+        assert_eq!(sign("s3cr3t", br#"[{"key":"k","value":"v"}]"#), headers["x-malka-signature"]);
+    }
+
+    #[tokio::test]
+    async fn should_retry_when_webhook_is_throttling_or_failing() {
+        let (throttled_address, _) = serve(StatusCode::TOO_MANY_REQUESTS);
+        let result = listener(throttled_address, None).consume(&[InFlightRecord::create(None, None)]).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Throttled(_))));
+
+        let (failing_address, _) = serve(StatusCode::BAD_GATEWAY);
+        let result = listener(failing_address, None).consume(&[InFlightRecord::create(None, None)]).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(failure) if failure.is_retriable()));
+
+        let (rejecting_address, _) = serve(StatusCode::BAD_REQUEST);
+        let result = listener(rejecting_address, None).consume(&[InFlightRecord::create(None, None)]).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(failure) if !failure.is_retriable()));
+    }
+}
@@ -1,5 +1,12 @@
+use std::borrow::Cow;
+
 use async_trait::async_trait;
-use serde::{Serialize};
+use rdkafka::message::Timestamp;
+use serde::{Serialize, Serializer};
+
+use crate::error::Result;
+use crate::kafka::error::{KafkaConsumerError, RecordCoordinates};
+use crate::kafka::payload::PayloadEncoder;
 
 /// A Kafka Consumer wrapper. Created, basically, to leverage proper
 /// unit testing when subscribing and consuming messages.
@@ -13,46 +20,147 @@ pub trait KafkaConsumer<LISTENER>
 /// Represents a consumer Kafka transaction.
 #[async_trait]
 pub trait KafkaConsumerTransaction {
-    async fn commit(&self);
-    async fn rollback(&self);
+    async fn commit(&self) -> Result<()>;
+    async fn rollback(&self) -> Result<()>;
+    /// Temporarily stops consuming from the partitions involved in the current transaction.
+    async fn pause(&self) -> Result<()>;
+    /// Sends the given records of the current transaction (all of them,
+    /// if `None`) to the dead-letter topic.
+    async fn dead_letter(&self, records: Option<&[RecordCoordinates]>) -> Result<()>;
+    /// Ends the current transaction without committing it, moving on to the next records.
+    async fn discard(&self) -> Result<()>;
 }
 
 /// The resulting outcome of a message consumption.
 #[derive(Debug,PartialEq,Clone)]
 pub enum KafkaConsumerResult {
-    Succeeded, NoMessagesConsumed, Failed(String)
+    Succeeded, NoMessagesConsumed, Failed(KafkaConsumerError)
 }
 
 /// Defines a listener for the Kafka consumer.
 #[async_trait]
 pub trait KafkaConsumerListener {
-    async fn consume(&self, record: Vec<InFlightRecord>) -> KafkaConsumerResult;
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult;
+
+    /// The biggest payload, in bytes, this listener is able to deliver.
+    fn max_payload_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// The biggest payload, in bytes, a single record can be delivered in. It only
+    /// differs from `max_payload_size` for listeners delivering records one at a time.
+    fn max_record_size(&self) -> Option<usize> {
+        self.max_payload_size()
+    }
+
+    /// Whether consumed records should be committed. Listeners that only validate
+    /// records (e.g. `DryRun` invocations) have them discarded instead.
+    fn commits_offsets(&self) -> bool {
+        true
+    }
+
+    /// Size, in bytes, of a payload of this listener without records. Listeners that
+    /// don't deliver payloads encoded by the subscription `encoder` measure their own.
+    fn empty_payload_size(&self, encoder: &PayloadEncoder) -> usize {
+        encoder.empty_payload_size()
+    }
+
+    /// Number of bytes `record` adds to a payload of this listener that already
+    /// holds `records_in_payload` records. See `empty_payload_size`.
+    fn payload_size_increment(&self, encoder: &PayloadEncoder, record: &InFlightRecord, records_in_payload: usize) -> usize {
+        encoder.payload_size_increment(record, records_in_payload)
+    }
 }
 
-/// Represents an in-flight message.
-#[derive(Serialize)]
+/// Size, in bytes, of an empty JSON array payload (`[]`).
+pub const EMPTY_PAYLOAD_SIZE: usize = 2;
+
+/// Represents an in-flight message. Its key and value are kept as received,
+/// and only serialized as (lossy) UTF-8 strings into JSON payloads.
+#[derive(Serialize, Clone)]
 pub struct InFlightRecord {
-    pub key: Option<String>,
-    pub value: Option<String>
+    #[serde(serialize_with = "serialize_as_str")]
+    pub key: Option<Vec<u8>>,
+    #[serde(serialize_with = "serialize_as_str")]
+    pub value: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oversized_value: Option<OversizedValue>,
+    #[serde(skip)]
+    pub topic: String,
+    #[serde(skip)]
+    pub partition: i32,
+    #[serde(skip)]
+    pub offset: i64,
+    #[serde(skip)]
+    pub timestamp: Timestamp,
+    #[serde(skip)]
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// The only target this record should be delivered to. Delivered to every target when absent.
+    #[serde(skip)]
+    pub route: Option<String>
+}
+
+/// Describes a value that was too big to be sent along with its record.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct OversizedValue {
+    pub size: usize,
+    /// Size of the key, when it had to be dropped as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_size: Option<usize>,
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>
 }
 
 impl InFlightRecord {
 
     pub fn create(key: Option<&[u8]>, value: Option<&[u8]>) -> Self {
         InFlightRecord {
-            key: key.map(InFlightRecord::convert_bytes_to_str),
-            value: value.map(InFlightRecord::convert_bytes_to_str)
+            key: key.map(<[u8]>::to_vec),
+            value: value.map(<[u8]>::to_vec),
+            oversized_value: None,
+            topic: String::new(),
+            partition: 0,
+            offset: 0,
+            timestamp: Timestamp::NotAvailable,
+            headers: Vec::new(),
+            route: None
         }
     }
 
-    fn convert_bytes_to_str(bytes: &[u8]) -> String {
-        std::str::from_utf8(bytes).unwrap().to_string()
+    /// Number of bytes this record adds to a JSON array payload
+    /// that already holds `records_in_payload` records.
+    pub fn payload_size_increment(&self, records_in_payload: usize) -> usize {
+        let separator = if records_in_payload > 0 { 1 } else { 0 };
+        let serialized = serde_json::to_vec(self).expect("Failed to serialize message");
+        serialized.len() + separator
     }
+
+    /// Where this record was read from.
+    pub fn coordinates(&self) -> RecordCoordinates {
+        (self.topic.clone(), self.partition, self.offset)
+    }
+
+    /// The key as a string, with invalid UTF-8 sequences replaced.
+    pub fn key_as_str(&self) -> Option<Cow<'_, str>> {
+        self.key.as_deref().map(String::from_utf8_lossy)
+    }
+
+    /// The value as a string, with invalid UTF-8 sequences replaced.
+    pub fn value_as_str(&self) -> Option<Cow<'_, str>> {
+        self.value.as_deref().map(String::from_utf8_lossy)
+    }
+}
+
+fn serialize_as_str<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    bytes.as_deref().map(String::from_utf8_lossy).serialize(serializer)
 }
 
 #[cfg(test)]
 mod json_tests {
-    use crate::kafka::consumer::InFlightRecord;
+    use crate::kafka::consumer::{InFlightRecord, EMPTY_PAYLOAD_SIZE};
 
     const EXPECTED_KEY: &str = "my_key";
     const EXPECTED_VALUE: &str = "{\"hello\":\"world\"}";
@@ -67,6 +175,33 @@ mod json_tests {
         let json_string = serde_json::to_string(&record).expect("Failed to serialize message");
         assert_eq!(EXPECTED_JSON, json_string)
     }
+
+    #[test]
+    fn should_keep_values_that_are_not_valid_utf8() {
+        let value = [b'h', b'i', 0xff];
+        let record = InFlightRecord::create(None, Some(&value));
+        assert_eq!(Some(value.to_vec()), record.value);
+
+        let json_string = serde_json::to_string(&record).expect("Failed to serialize message");
+        assert_eq!("{\"key\":null,\"value\":\"hi\u{fffd}\"}", json_string)
+    }
+
+    #[test]
+    fn should_measure_the_payload_size_increment_of_each_record() {
+        let records = vec!(
+            InFlightRecord::create(Some(EXPECTED_KEY.as_bytes()), Some(EXPECTED_VALUE.as_bytes())),
+            InFlightRecord::create(None, Some(EXPECTED_VALUE.as_bytes())),
+            InFlightRecord::create(None, None)
+        );
+
+        let mut measured_size = EMPTY_PAYLOAD_SIZE;
+        for (records_in_payload, record) in records.iter().enumerate() {
+            measured_size += record.payload_size_increment(records_in_payload);
+        }
+
+        let json_string = serde_json::to_string(&records).expect("Failed to serialize message");
+        assert_eq!(json_string.len(), measured_size)
+    }
 }
 
 #[cfg(test)]
@@ -84,21 +219,51 @@ pub(crate) mod mocks {
         consume_expected_result: KafkaConsumerResult,
         rollback_called: Arc<AtomicBool>,
         commit_called: Arc<AtomicBool>,
+        pause_called: Arc<AtomicBool>,
+        dead_letter_called: Arc<AtomicBool>,
+        discard_called: Arc<AtomicBool>,
     }
 
     impl MockKafkaConsumerAndTransaction {
         pub fn new() -> Self {
+            MockKafkaConsumerAndTransaction::returning(KafkaConsumerResult::Succeeded)
+        }
+
+        pub fn returning(consume_expected_result: KafkaConsumerResult) -> Self {
             MockKafkaConsumerAndTransaction {
                 consume_called: Arc::new(Default::default()),
-                consume_expected_result: KafkaConsumerResult::Succeeded,
+                consume_expected_result,
                 commit_called: Arc::new(Default::default()),
                 rollback_called: Arc::new(Default::default()),
+                pause_called: Arc::new(Default::default()),
+                dead_letter_called: Arc::new(Default::default()),
+                discard_called: Arc::new(Default::default()),
             }
         }
 
         pub fn reference_to_check_if_consumer_has_been_called(&self) -> Arc<AtomicBool> {
             Arc::clone(&self.consume_called)
         }
+
+        pub fn reference_to_check_if_rollback_has_been_called(&self) -> Arc<AtomicBool> {
+            Arc::clone(&self.rollback_called)
+        }
+
+        pub fn reference_to_check_if_pause_has_been_called(&self) -> Arc<AtomicBool> {
+            Arc::clone(&self.pause_called)
+        }
+
+        pub fn reference_to_check_if_commit_has_been_called(&self) -> Arc<AtomicBool> {
+            Arc::clone(&self.commit_called)
+        }
+
+        pub fn reference_to_check_if_dead_letter_has_been_called(&self) -> Arc<AtomicBool> {
+            Arc::clone(&self.dead_letter_called)
+        }
+
+        pub fn reference_to_check_if_discard_has_been_called(&self) -> Arc<AtomicBool> {
+            Arc::clone(&self.discard_called)
+        }
     }
 
     #[async_trait]
@@ -116,20 +281,39 @@ pub(crate) mod mocks {
     impl KafkaConsumerTransaction
         for MockKafkaConsumerAndTransaction {
 
-        async fn commit(&self) {
+        async fn commit(&self) -> Result<()> {
             tokio::time::sleep_until(Instant::now().add(Duration::from_secs(1))).await;
             self.commit_called.store(true, Release);
+            Ok(())
         }
 
-        async fn rollback(&self) {
+        async fn rollback(&self) -> Result<()> {
             tokio::time::sleep(Duration::from_millis(500)).await;
             self.rollback_called.store(true, Release);
+            Ok(())
+        }
+
+        async fn pause(&self) -> Result<()> {
+            self.pause_called.store(true, Release);
+            Ok(())
+        }
+
+        async fn dead_letter(&self, _records: Option<&[RecordCoordinates]>) -> Result<()> {
+            self.dead_letter_called.store(true, Release);
+            Ok(())
+        }
+
+        async fn discard(&self) -> Result<()> {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.discard_called.store(true, Release);
+            Ok(())
         }
     }
 
     pub struct MockKafkaConsumerListener {
         consume_called: Arc<AtomicBool>,
-        consume_expected_result: KafkaConsumerResult
+        consume_expected_result: KafkaConsumerResult,
+        commits_offsets: bool
     }
 
     impl MockKafkaConsumerListener {
@@ -137,10 +321,15 @@ pub(crate) mod mocks {
         pub fn new() -> MockKafkaConsumerListener {
             MockKafkaConsumerListener {
                 consume_called: Arc::new(Default::default()),
-                consume_expected_result: KafkaConsumerResult::Succeeded
+                consume_expected_result: KafkaConsumerResult::Succeeded,
+                commits_offsets: true
             }
         }
 
+        pub fn validating() -> MockKafkaConsumerListener {
+            MockKafkaConsumerListener { commits_offsets: false, ..MockKafkaConsumerListener::new() }
+        }
+
         pub fn reference_to_check_if_consumer_has_been_called(&self) -> Arc<AtomicBool> {
             Arc::clone(&self.consume_called)
         }
@@ -149,11 +338,15 @@ pub(crate) mod mocks {
     #[async_trait]
     impl KafkaConsumerListener
     for MockKafkaConsumerListener {
-        async fn consume(&self, _record: Vec<InFlightRecord>) -> KafkaConsumerResult {
+        async fn consume(&self, _records: &[InFlightRecord]) -> KafkaConsumerResult {
             self.consume_called.store(true, Release);
             tokio::time::sleep(Duration::from_millis(100)).await;
             self.consume_expected_result.clone()
         }
+
+        fn commits_offsets(&self) -> bool {
+            self.commits_offsets
+        }
     }
 }
 
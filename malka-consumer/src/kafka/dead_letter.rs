@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use log::warn;
+use rdkafka::ClientConfig;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use crate::error::Result;
+use crate::kafka::consumer::InFlightRecord;
+
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const HEADER_SOURCE_TOPIC: &str = "malka.source.topic";
+pub const HEADER_SOURCE_PARTITION: &str = "malka.source.partition";
+pub const HEADER_SOURCE_OFFSET: &str = "malka.source.offset";
+
+/// Publishes records that couldn't be delivered into a dead-letter topic.
+/// The original record coordinates are kept as `malka.source.*` headers.
+pub struct DeadLetterPublisher {
+    producer: FutureProducer,
+    topic_name: String
+}
+
+impl DeadLetterPublisher {
+    pub fn create(topic_name: String, cfg: ClientConfig) -> Result<Self> {
+        let producer: FutureProducer = cfg.create()?;
+        Ok(DeadLetterPublisher { producer, topic_name })
+    }
+
+    /// Publishes `record` and waits for its delivery report.
+    pub async fn publish(&self, record: &InFlightRecord) -> Result<()> {
+        warn!("Sending record {}-{}@{} to dead-letter topic {}",
+              &record.topic, record.partition, record.offset, &self.topic_name);
+
+        let headers = OwnedHeaders::new()
+            .add(HEADER_SOURCE_TOPIC, &record.topic)
+            .add(HEADER_SOURCE_PARTITION, &record.partition.to_string())
+            .add(HEADER_SOURCE_OFFSET, &record.offset.to_string());
+
+        let mut dead_letter: FutureRecord<[u8], [u8]> = FutureRecord::to(&self.topic_name)
+            .headers(headers);
+        if let Some(key) = &record.key {
+            dead_letter = dead_letter.key(key.as_slice());
+        }
+        if let Some(value) = &record.value {
+            dead_letter = dead_letter.payload(value.as_slice());
+        }
+
+        self.producer.send(dead_letter, QUEUE_TIMEOUT).await
+            .map_err(|(cause, _)| cause)?;
+        Ok(())
+    }
+}
@@ -1,14 +1,26 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use rdkafka::{ClientConfig, Message, TopicPartitionList};
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
 use rdkafka::consumer::{CommitMode, Consumer, DefaultConsumerContext, BaseConsumer};
-use rdkafka::message::BorrowedMessage;
+use rdkafka::message::{BorrowedMessage, Headers};
 use rdkafka::util::Timeout;
-use log::{debug, trace};
+use log::{debug, error, info, trace, warn};
 
-use crate::error::Result;
+use crate::backoff::Backoff;
+use crate::conf::SubscriptionConfig;
+use crate::error::{KnownHandledErrors, Result};
 use crate::kafka::consumer::{InFlightRecord, KafkaConsumer, KafkaConsumerListener, KafkaConsumerResult, KafkaConsumerTransaction};
+use crate::kafka::dead_letter::DeadLetterPublisher;
+use crate::kafka::error::{FailureCause, KafkaConsumerError, RecordCoordinates};
+use crate::kafka::filter::{METRIC_FILTERED_RECORDS, RecordFilter};
+use crate::kafka::oversized::OversizedRecordHandler;
+use crate::kafka::payload::PayloadEncoder;
+use crate::kafka::router::{METRIC_UNROUTED_RECORDS, RecordRouter};
+use crate::kafka::transform::WasmRecordTransformer;
+use crate::metrics;
 
 const MSG_FAIL_TO_POLL: &str = "Could not poll messages.";
 const MSG_FAIL_TO_COMMIT: &str = "Could not commit message. Interrupting this consumer to avoid data loss.";
@@ -22,39 +34,110 @@ const KAFKA_TIMEOUT: Timeout = Timeout::After(TIMEOUT);
 pub struct DefaultKafkaConsumer {
     stream_consumer: BaseConsumer<DefaultConsumerContext>,
     max_buffer_size: usize,
+    max_buffer_bytes: usize,
     max_buffer_await_time: Duration,
     group_instance_id: String,
+    oversized_record_handler: OversizedRecordHandler,
+    dead_letter: Option<DeadLetterPublisher>,
+    /// Measures how big the payload delivered to the listener is.
+    payload_encoder: PayloadEncoder,
+    /// Decides which records are delivered to the listener.
+    record_filter: RecordFilter,
+    /// Picks the target function each record is delivered to.
+    record_router: RecordRouter,
+    /// Filters and reshapes records before they're delivered to the listener.
+    record_transformer: Option<WasmRecordTransformer>,
+    /// The records delivered to the listener in the current transaction.
+    in_flight_records: Mutex<Vec<InFlightRecord>>,
+    /// A record that didn't fit in the previous batch and should open the next one.
+    overflow_record: Mutex<Option<InFlightRecord>>,
+    /// The offsets to be committed once the current batch is successfully consumed.
+    pending_offsets: Mutex<HashMap<(String, i32), Offset>>,
+    throttling_backoff: Mutex<Backoff>,
+    /// Partitions paused due to throttling, and when each of them should be resumed.
+    paused_partitions: Mutex<HashMap<(String, i32), Instant>>,
 }
 
 impl DefaultKafkaConsumer {
     pub fn create(
         group_instance_id: String,
-        topic_name: String,
-        max_buffer_size: usize,
-        max_buffer_await_time_millis: u64,
+        subscription: &SubscriptionConfig,
+        oversized_record_handler: OversizedRecordHandler,
+        dead_letter: Option<DeadLetterPublisher>,
         cfg: ClientConfig
     ) -> Result<Self> {
+        let payload_encoder = PayloadEncoder::create(
+            subscription.payload_format,
+            cfg.get("bootstrap.servers").unwrap_or_default().to_string());
+        let record_filter = RecordFilter::create(&subscription.filters)?;
+        let record_router = RecordRouter::create(&subscription.routes, &subscription.target_functions)?;
+        let record_transformer = subscription.wasm_transform.as_deref()
+            .map(|path| WasmRecordTransformer::create(path, subscription.wasm_transform_fuel, &subscription.target_functions))
+            .transpose()?;
         let context = DefaultConsumerContext {};
         let stream_consumer: BaseConsumer<DefaultConsumerContext> = cfg.create_with_context(context)?;
-        stream_consumer.subscribe(&[&topic_name])?;
+        stream_consumer.subscribe(&[&subscription.topic_name])?;
+
+        let throttling_backoff = Backoff::new(
+            Duration::from_millis(subscription.topic_throttling_backoff_time),
+            Duration::from_millis(subscription.topic_max_throttling_backoff_time));
 
         Ok(DefaultKafkaConsumer {
             group_instance_id,
             stream_consumer,
-            max_buffer_await_time: Duration::from_millis(max_buffer_await_time_millis),
-            max_buffer_size
+            max_buffer_await_time: Duration::from_millis(subscription.topic_max_buffer_await_time),
+            max_buffer_size: subscription.topic_max_buffer_size,
+            max_buffer_bytes: subscription.topic_max_buffer_bytes,
+            oversized_record_handler,
+            dead_letter,
+            payload_encoder,
+            record_filter,
+            record_router,
+            record_transformer,
+            in_flight_records: Mutex::new(Vec::new()),
+            overflow_record: Mutex::new(None),
+            pending_offsets: Mutex::new(HashMap::new()),
+            throttling_backoff: Mutex::new(throttling_backoff),
+            paused_partitions: Mutex::new(HashMap::new())
         })
     }
 
     fn read_received_message(&self, msg: &BorrowedMessage) -> InFlightRecord {
-        InFlightRecord::create(
-            msg.key(),
-            msg.payload()
-        )
+        InFlightRecord {
+            topic: msg.topic().to_string(),
+            partition: msg.partition(),
+            offset: msg.offset(),
+            timestamp: msg.timestamp(),
+            headers: msg.headers()
+                .map(|headers| (0..headers.count())
+                    .filter_map(|index| headers.get(index))
+                    .map(|(name, value)| (name.to_string(), value.to_vec()))
+                    .collect())
+                .unwrap_or_default(),
+            ..InFlightRecord::create(msg.key(), msg.payload())
+        }
     }
 
-    async fn consume_and_buffer_messages(&self) -> Result<Vec<InFlightRecord>> {
+    /// Buffers messages until either `max_buffer_size` records were received,
+    /// `max_buffer_await_time` has elapsed or the payload, as measured by
+    /// the `listener`, would exceed `max_buffer_bytes`. In the latter case, the record that didn't fit
+    /// is kept aside and will be the first one of the next batch. Records that
+    /// alone exceed `max_record_bytes` are handed to the `OversizedRecordHandler`.
+    async fn consume_and_buffer_messages<LISTENER>(&self, listener: &LISTENER, max_buffer_bytes: usize, max_record_bytes: usize) -> Result<Vec<InFlightRecord>>
+        where LISTENER: KafkaConsumerListener + std::marker::Sync
+    {
+        self.resume_paused_partitions_if_due();
+
         let mut buffer = Vec::new();
+        let mut buffer_bytes = listener.empty_payload_size(&self.payload_encoder);
+
+        let overflow_record = self.overflow_record.lock().unwrap().take();
+        if let Some(record) = overflow_record {
+            buffer_bytes += listener.payload_size_increment(&self.payload_encoder, &record, buffer.len());
+            self.memorize_offset_to_commit(&record.topic, record.partition, record.offset);
+            buffer.push(record);
+        }
+
         let start = Instant::now();
         let mut elapsed = start.elapsed();
         while elapsed <= self.max_buffer_await_time && buffer.len() < self.max_buffer_size {
@@ -62,14 +145,148 @@ impl DefaultKafkaConsumer {
             let optional_message = self.stream_consumer.poll(self.max_buffer_await_time);
             if let Some(result) = optional_message {
                 let message = result?;
-                let record = self.read_received_message(&message);
-                buffer.push(record);
+                if let Some(record) = self.read_and_check_record_size(&message, listener, max_record_bytes).await? {
+                    let record_bytes = listener.payload_size_increment(&self.payload_encoder, &record, buffer.len());
+                    if !buffer.is_empty() && buffer_bytes + record_bytes > max_buffer_bytes {
+                        trace!("[{}] Max buffer bytes reached. Deferring record to the next batch.", &self.group_instance_id);
+                        *self.overflow_record.lock().unwrap() = Some(record);
+                        break;
+                    }
+
+                    buffer_bytes += record_bytes;
+                    self.memorize_offset_to_commit(&record.topic, record.partition, record.offset);
+                    buffer.push(record);
+                }
             }
 
             elapsed = start.elapsed();
         }
         Ok(buffer)
     }
+
+    /// Reads the received message. Records that can't fit in a payload on their own
+    /// are handed to the `OversizedRecordHandler`, in which case `None` is returned
+    /// if there's nothing left to deliver, as well as for records left out by the filters
+    /// or matching none of the routes.
+    async fn read_and_check_record_size<LISTENER>(&self, msg: &BorrowedMessage<'_>, listener: &LISTENER, max_record_bytes: usize) -> Result<Option<InFlightRecord>>
+        where LISTENER: KafkaConsumerListener + std::marker::Sync
+    {
+        let mut record = self.read_received_message(msg);
+        if !self.record_filter.accepts(&record) {
+            trace!("[{}] Record {}-{}@{} filtered out.", &self.group_instance_id, &record.topic, record.partition, record.offset);
+            metrics::global().increment(METRIC_FILTERED_RECORDS, &[("topic", &record.topic)]);
+            self.memorize_offset_to_commit(msg.topic(), msg.partition(), msg.offset());
+            return Ok(None)
+        }
+        if !self.record_router.route(&mut record) {
+            warn!("[{}] Record {}-{}@{} matches no route. Skipping it.", &self.group_instance_id, &record.topic, record.partition, record.offset);
+            metrics::global().increment(METRIC_UNROUTED_RECORDS, &[("topic", &record.topic)]);
+            self.memorize_offset_to_commit(msg.topic(), msg.partition(), msg.offset());
+            return Ok(None)
+        }
+
+        let fits = |record: &InFlightRecord| self.fits(listener, record, max_record_bytes);
+        if fits(&record) {
+            return Ok(Some(record))
+        }
+
+        let handled_record = self.oversized_record_handler.handle(record, fits).await?;
+        if handled_record.is_none() {
+            self.memorize_offset_to_commit(msg.topic(), msg.partition(), msg.offset());
+        }
+        Ok(handled_record)
+    }
+
+    /// Whether `record` fits, on its own, in a payload of `max_record_bytes`.
+    fn fits<LISTENER>(&self, listener: &LISTENER, record: &InFlightRecord, max_record_bytes: usize) -> bool
+        where LISTENER: KafkaConsumerListener + std::marker::Sync
+    {
+        listener.empty_payload_size(&self.payload_encoder)
+            + listener.payload_size_increment(&self.payload_encoder, record, 0) <= max_record_bytes
+    }
+
+    /// Runs the records through the transformer. As transforms may grow records, the
+    /// ones that no longer fit on their own are handed to the `OversizedRecordHandler`.
+    async fn transform<LISTENER>(&self, transformer: &WasmRecordTransformer, listener: &LISTENER,
+                                 records: &[InFlightRecord], max_record_bytes: usize) -> std::result::Result<Vec<InFlightRecord>, KafkaConsumerError>
+        where LISTENER: KafkaConsumerListener + std::marker::Sync
+    {
+        let transformed = transformer.transform(records).await.map_err(KafkaConsumerError::InvalidPayload)?;
+        let fits = |record: &InFlightRecord| self.fits(listener, record, max_record_bytes);
+
+        let mut records = Vec::with_capacity(transformed.len());
+        for record in transformed {
+            if fits(&record) {
+                records.push(record);
+                continue
+            }
+
+            let handled_record = self.oversized_record_handler.handle(record, fits).await.map_err(|failure| {
+                let msg = format!("[{}] Could not handle oversized transformed record.", &self.group_instance_id);
+                KafkaConsumerError::Poll(FailureCause::with_source(msg, failure))
+            })?;
+            records.extend(handled_record);
+        }
+        Ok(records)
+    }
+
+    /// Hands the records to the listener in as many payloads as `max_buffer_bytes` requires,
+    /// as transformed records may no longer fit in a single one. Failures only affect the
+    /// records of the payload that failed.
+    async fn consume_in_payloads<LISTENER>(&self, listener: &LISTENER, records: &[InFlightRecord], max_buffer_bytes: usize) -> KafkaConsumerResult
+        where LISTENER: KafkaConsumerListener + std::marker::Sync
+    {
+        let payloads = split_into_payloads(listener, &self.payload_encoder, records, max_buffer_bytes);
+        if payloads.len() > 1 {
+            debug!("[{}] Transformed records don't fit in a single payload. Consuming them in {} payloads.", &self.group_instance_id, payloads.len());
+        }
+
+        let mut failures = Vec::new();
+        for payload in payloads {
+            if let KafkaConsumerResult::Failed(failure) = listener.consume(payload).await {
+                let failure = match failure.cause().records() {
+                    Some(_) => failure,
+                    None => failure.affecting(payload.iter().map(InFlightRecord::coordinates).collect())
+                };
+                failures.push(failure);
+            }
+        }
+
+        match KafkaConsumerError::prevailing(failures) {
+            Some(cause) => KafkaConsumerResult::Failed(cause),
+            None => KafkaConsumerResult::Succeeded
+        }
+    }
+
+    /// Keeps track of the offsets that should be committed once the current batch
+    /// is consumed. Only handled records should be memorized, so records carried
+    /// over to the next batch won't be accidentally committed.
+    fn memorize_offset_to_commit(&self, topic: &str, partition: i32, offset: i64) {
+        let next_offset = Offset::Offset(offset + 1);
+        self.pending_offsets.lock().unwrap()
+            .insert((topic.to_string(), partition), next_offset);
+    }
+
+    /// Resumes the partitions paused due to throttling once their backoff time is over.
+    fn resume_paused_partitions_if_due(&self) {
+        let due_partitions = take_due_partitions(&mut self.paused_partitions.lock().unwrap(), Instant::now());
+        if due_partitions.is_empty() {
+            return
+        }
+
+        let mut partitions = TopicPartitionList::new();
+        for (topic, partition) in &due_partitions {
+            partitions.add_partition(topic, *partition);
+        }
+        info!("[{}] Resuming {} paused partition(s).", &self.group_instance_id, partitions.count());
+        if let Err(cause) = self.stream_consumer.resume(&partitions) {
+            error!("[{}] Could not resume partitions. \nDetails: {:?}", &self.group_instance_id, cause);
+        }
+    }
+
+    fn has_offsets_to_commit(&self) -> bool {
+        !self.pending_offsets.lock().unwrap().is_empty()
+    }
 }
 
 #[async_trait]
@@ -77,19 +294,50 @@ impl<LISTENER> KafkaConsumer<LISTENER> for DefaultKafkaConsumer
     where LISTENER: KafkaConsumerListener + std::marker::Sync {
 
     async fn consume(&self, listener: &LISTENER) -> KafkaConsumerResult {
-        match self.consume_and_buffer_messages().await {
+        let max_buffer_bytes = listener.max_payload_size()
+            .map_or(self.max_buffer_bytes, |max_payload_size| max_payload_size.min(self.max_buffer_bytes));
+        let max_record_bytes = listener.max_record_size()
+            .map_or(max_buffer_bytes, |max_record_size| max_record_size.min(max_buffer_bytes));
+
+        match self.consume_and_buffer_messages(listener, max_buffer_bytes, max_record_bytes).await {
+            Ok(received_message) if received_message.is_empty() && self.has_offsets_to_commit() => {
+                debug!("[{}] All received messages were handled without being delivered.", &self.group_instance_id);
+                KafkaConsumerResult::Succeeded
+            },
             Ok(received_message) if received_message.is_empty() => {
                 debug!("[{}] No messages received.", &self.group_instance_id);
                 KafkaConsumerResult::NoMessagesConsumed
             },
             Ok(received_message) => {
-                debug!("[{}] Consuming {} message(s)", &self.group_instance_id, received_message.len());
-                let result = listener.consume(received_message).await;
+                let transformed = match &self.record_transformer {
+                    None => None,
+                    Some(transformer) => match self.transform(transformer, listener, &received_message, max_record_bytes).await {
+                        Ok(transformed) => Some(transformed),
+                        Err(failure) => {
+                            *self.in_flight_records.lock().unwrap() = received_message;
+                            return KafkaConsumerResult::Failed(failure)
+                        }
+                    }
+                };
+
+                let records = transformed.as_deref().unwrap_or(&received_message);
+                if records.is_empty() {
+                    debug!("[{}] All received messages were dropped by the transformer.", &self.group_instance_id);
+                    return KafkaConsumerResult::Succeeded
+                }
+
+                debug!("[{}] Consuming {} message(s)", &self.group_instance_id, records.len());
+                let result = match &transformed {
+                    None => listener.consume(records).await,
+                    Some(_) => self.consume_in_payloads(listener, records, max_buffer_bytes).await
+                };
+                // Dead-letters the records as received, so they can be transformed again once republished.
+                *self.in_flight_records.lock().unwrap() = received_message;
                 result
             },
             Err(failure) => {
-                let msg = format!("[{}] {}. \nDetails: {:?}", &self.group_instance_id, MSG_FAIL_TO_POLL, failure);
-                KafkaConsumerResult::Failed(msg)
+                let msg = format!("[{}] {}", &self.group_instance_id, MSG_FAIL_TO_POLL);
+                KafkaConsumerResult::Failed(KafkaConsumerError::Poll(FailureCause::with_source(msg, failure)))
             }
         }
     }
@@ -99,21 +347,188 @@ impl<LISTENER> KafkaConsumer<LISTENER> for DefaultKafkaConsumer
 impl KafkaConsumerTransaction
  for DefaultKafkaConsumer {
 
-    async fn commit(&self) {
-        let result = self.stream_consumer.commit_consumer_state(CommitMode::Sync);
+    async fn commit(&self) -> Result<()> {
+        self.in_flight_records.lock().unwrap().clear();
+        let offsets = std::mem::take(&mut *self.pending_offsets.lock().unwrap());
+        let result = TopicPartitionList::from_topic_map(&offsets)
+            .and_then(|offsets| self.stream_consumer.commit(&offsets, CommitMode::Sync));
         if let Err(cause) = result {
-            panic!("[{}] {}. \nDetails: {:?}", &self.group_instance_id, MSG_FAIL_TO_COMMIT, cause)
+            error!("[{}] {} \nDetails: {:?}", &self.group_instance_id, MSG_FAIL_TO_COMMIT, cause);
+            return Err(cause.into())
         }
+        self.throttling_backoff.lock().unwrap().reset();
+        Ok(())
     }
 
-    async fn rollback(&self) {
-        let committed: TopicPartitionList = self.stream_consumer.committed(KAFKA_TIMEOUT)
-            .expect(MSG_FAIL_TO_ROLLBACK);
+    async fn rollback(&self) -> Result<()> {
+        self.in_flight_records.lock().unwrap().clear();
+        self.overflow_record.lock().unwrap().take();
+        self.pending_offsets.lock().unwrap().clear();
 
-        for ((topic,partition), offset) in committed.to_topic_map() {
-            self.stream_consumer.seek(&topic, partition, offset, KAFKA_TIMEOUT)
-                .expect(MSG_FAIL_TO_ROLLBACK)
+        let result = self.stream_consumer.committed(KAFKA_TIMEOUT)
+            .and_then(|committed| {
+                for ((topic, partition), offset) in committed.to_topic_map() {
+                    self.stream_consumer.seek(&topic, partition, offset, KAFKA_TIMEOUT)?;
+                }
+                Ok(())
+            });
+        if let Err(cause) = result {
+            error!("[{}] {} \nDetails: {:?}", &self.group_instance_id, MSG_FAIL_TO_ROLLBACK, cause);
+            return Err(cause.into())
         }
+        Ok(())
+    }
+
+    async fn discard(&self) -> Result<()> {
+        self.in_flight_records.lock().unwrap().clear();
+        self.pending_offsets.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn pause(&self) -> Result<()> {
+        let pending_partitions: Vec<(String, i32)> = self.pending_offsets.lock().unwrap().keys().cloned().collect();
+        if pending_partitions.is_empty() {
+            return Ok(())
+        }
+
+        let mut partitions = TopicPartitionList::new();
+        for (topic, partition) in &pending_partitions {
+            partitions.add_partition(topic, *partition);
+        }
+
+        let delay = self.throttling_backoff.lock().unwrap().next_delay();
+        warn!("[{}] Pausing {} partition(s) for {:?}.", &self.group_instance_id, partitions.count(), delay);
+        self.stream_consumer.pause(&partitions)?;
+
+        // Partitions paused earlier for other batches keep their own resume time.
+        let resume_at = Instant::now() + delay;
+        let mut paused_partitions = self.paused_partitions.lock().unwrap();
+        for topic_partition in pending_partitions {
+            paused_partitions.insert(topic_partition, resume_at);
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(&self, failed_records: Option<&[RecordCoordinates]>) -> Result<()> {
+        let dead_letter = self.dead_letter.as_ref()
+            .ok_or(KnownHandledErrors::DeadLetterTopicNotConfigured)?;
+
+        let records = std::mem::take(&mut *self.in_flight_records.lock().unwrap());
+        let is_failed = |record: &InFlightRecord| failed_records.is_none_or(|failed_records| failed_records.iter()
+            .any(|(topic, partition, offset)| *topic == record.topic && *partition == record.partition && *offset == record.offset));
+        for record in records.iter().filter(|record| is_failed(record)) {
+            dead_letter.publish(record).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes the partitions that are due to be resumed at `now` from `paused_partitions`.
+fn take_due_partitions(paused_partitions: &mut HashMap<(String, i32), Instant>, now: Instant) -> Vec<(String, i32)> {
+    let due_partitions: Vec<(String, i32)> = paused_partitions.iter()
+        .filter(|(_, resume_at)| **resume_at <= now)
+        .map(|(topic_partition, _)| topic_partition.clone())
+        .collect();
+    for topic_partition in &due_partitions {
+        paused_partitions.remove(topic_partition);
+    }
+    due_partitions
+}
+
+/// Splits records into payloads of up to `max_buffer_bytes`, as measured by the `listener`.
+fn split_into_payloads<'a, LISTENER>(listener: &LISTENER, encoder: &PayloadEncoder, records: &'a [InFlightRecord], max_buffer_bytes: usize) -> Vec<&'a [InFlightRecord]>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync
+{
+    let empty_payload_size = listener.empty_payload_size(encoder);
+    let mut payloads = Vec::new();
+    let mut start = 0;
+    let mut payload_bytes = empty_payload_size;
+    for (index, record) in records.iter().enumerate() {
+        let record_bytes = listener.payload_size_increment(encoder, record, index - start);
+        if index > start && payload_bytes + record_bytes > max_buffer_bytes {
+            payloads.push(&records[start..index]);
+            start = index;
+            payload_bytes = empty_payload_size + listener.payload_size_increment(encoder, record, 0);
+        } else {
+            payload_bytes += record_bytes;
+        }
+    }
+    if start < records.len() {
+        payloads.push(&records[start..]);
+    }
+    payloads
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use rdkafka::ClientConfig;
+
+    use crate::conf::{OversizedRecordPolicy, PayloadFormat, SubscriptionConfig};
+    use crate::kafka::consumer::InFlightRecord;
+    use crate::kafka::consumer::mocks::MockKafkaConsumerListener;
+    use crate::kafka::oversized::OversizedRecordHandler;
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::{DefaultKafkaConsumer, split_into_payloads, take_due_partitions};
+
+    fn records(count: i64) -> Vec<InFlightRecord> {
+        (0..count).map(|offset| InFlightRecord { offset, ..InFlightRecord::create(None, Some("vvvvvvvvvv".as_bytes())) }).collect()
+    }
+
+    #[test]
+    fn should_only_take_the_partitions_due_to_be_resumed() {
+        let now = Instant::now();
+        let mut paused_partitions = HashMap::new();
+        paused_partitions.insert(("user.delete".to_string(), 0), now - Duration::from_secs(1));
+        paused_partitions.insert(("user.delete".to_string(), 1), now + Duration::from_secs(5));
+
+        assert_eq!(vec!(("user.delete".to_string(), 0)), take_due_partitions(&mut paused_partitions, now));
+        assert!(take_due_partitions(&mut paused_partitions, now).is_empty());
+        assert_eq!(vec!(("user.delete".to_string(), 1)), take_due_partitions(&mut paused_partitions, now + Duration::from_secs(5)));
+        assert!(paused_partitions.is_empty());
+    }
+
+    #[test]
+    fn should_split_records_into_payloads_fitting_the_buffer() {
+        let encoder = PayloadEncoder::create(PayloadFormat::Array, String::new());
+        let records = records(3);
+        let two_records_size = serde_json::to_vec(&records[..2]).unwrap().len();
+        let payloads = split_into_payloads(&MockKafkaConsumerListener::new(), &encoder, &records, two_records_size);
+        assert_eq!(vec!(2, 1), payloads.iter().map(|payload| payload.len()).collect::<Vec<_>>());
+        assert_eq!(1, split_into_payloads(&MockKafkaConsumerListener::new(), &encoder, &records, 1024).len());
+    }
+
+    #[tokio::test]
+    async fn should_handle_records_grown_by_the_transformer_as_oversized() {
+        let decision = format!(r#"{{"value":"{}"}}"#, "v".repeat(1024));
+        let wat = format!(r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 16) "{}")
+            (func (export "alloc") (param i32) (result i32) (i32.const 4096))
+            (func (export "transform") (param i32 i32) (result i64)
+                (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const {}))))"#, decision.replace('"', "\\\""), decision.len());
+        let module = std::env::temp_dir().join("malka-growing-transform.wasm");
+        std::fs::write(&module, wat::parse_str(wat).unwrap()).unwrap();
+
+        let subscription: SubscriptionConfig = serde_json::from_value(serde_json::json!({
+            "topic_name": "user.delete", "target_functions": ["user_deleted"], "wasm_transform": module
+        })).unwrap();
+        let mut config = ClientConfig::new();
+        config.set("group.id", "unit-test").set("bootstrap.servers", "127.0.0.1:1");
+        let consumer = DefaultKafkaConsumer::create(
+            "group_id_instance".to_string(),
+            &subscription,
+            OversizedRecordHandler::create(&OversizedRecordPolicy::MetadataOnly, ClientConfig::new()).unwrap(),
+            None,
+            config).unwrap();
+
+        let transformer = consumer.record_transformer.as_ref().unwrap();
+        let transformed = consumer.transform(transformer, &MockKafkaConsumerListener::new(), &records(1), 256).await.unwrap();
+        assert_eq!(None, transformed[0].value);
+        assert_eq!(Some(1024), transformed[0].oversized_value.as_ref().map(|oversized_value| oversized_value.size));
     }
 }
 
@@ -128,6 +543,8 @@ mod integration_tests {
     use crate::kafka::consumer::{KafkaConsumer, KafkaConsumerResult};
     use crate::kafka::consumer::mocks::MockKafkaConsumerListener;
     use crate::kafka::defaults::DefaultKafkaConsumer;
+    use crate::kafka::oversized::OversizedRecordHandler;
+    use crate::conf::{OversizedRecordPolicy, SubscriptionConfig};
 
     #[tokio::test]
     #[ignore]
@@ -155,10 +572,15 @@ mod integration_tests {
             .set("auto.offset.reset", "earliest")
             .set("fetch.wait.max.ms", "100")
             .set("batch.num.messages", "1");
+        let subscription: SubscriptionConfig = serde_json::from_str(r#"{
+            "topic_name": "test", "target_functions": [],
+            "topic_max_buffer_size": 1, "topic_max_buffer_bytes": 1024, "topic_max_buffer_await_time": 100
+        }"#).unwrap();
         let consumer = DefaultKafkaConsumer::create(
             "group_id_instance".to_string(),
-            "test".to_string(),
-            1, 100,
+            &subscription,
+            OversizedRecordHandler::create(&OversizedRecordPolicy::Skip, ClientConfig::new()).unwrap(),
+            None,
             config).unwrap();
         let result = consumer.consume(&listener).await;
 
@@ -174,6 +596,6 @@ mod integration_tests {
             .set("auto.commit.enable", "false")
             .set_log_level(RDKafkaLogLevel::Debug);
 
-        return cfg;
+        cfg
     }
 }
@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use futures::{FutureExt, StreamExt};
+
+use crate::conf::DispatchMode;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{KafkaConsumerError, RecordCoordinates};
+use crate::kafka::payload::PayloadEncoder;
+
+/// A `KafkaConsumerListener` that hands records to another listener according
+/// to the `DispatchMode`: either the whole batch at once, or one record at a
+/// time, in which case up to `max_concurrency` records are handed concurrently.
+pub struct RecordDispatcher<LISTENER>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync
+{
+    mode: DispatchMode,
+    listener: LISTENER
+}
+
+impl<LISTENER> RecordDispatcher<LISTENER>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync {
+
+    pub fn create(mode: DispatchMode, listener: LISTENER) -> Self {
+        RecordDispatcher { mode, listener }
+    }
+
+    /// Hands every record on its own. The batch only succeeds if all of them do.
+    /// Failures only affect the records that failed, so the ones that succeeded
+    /// won't be dead-lettered along with them.
+    async fn consume_each(&self, records: &[InFlightRecord], max_concurrency: usize) -> KafkaConsumerResult {
+        let mut invocations = Vec::with_capacity(records.len());
+        for record in records.chunks(1) {
+            let coordinates = record[0].coordinates();
+            invocations.push(self.listener.consume(record).map(move |result| (coordinates, result)));
+        }
+
+        let results: Vec<(RecordCoordinates, KafkaConsumerResult)> = futures::stream::iter(invocations)
+            .buffer_unordered(max_concurrency.max(1))
+            .collect()
+            .await;
+
+        let failures = results.into_iter()
+            .filter_map(|(coordinates, result)| match result {
+                KafkaConsumerResult::Failed(cause) => Some(cause.affecting(vec!(coordinates))),
+                _ => None
+            });
+
+        match KafkaConsumerError::prevailing(failures) {
+            Some(cause) => KafkaConsumerResult::Failed(cause),
+            None => KafkaConsumerResult::Succeeded
+        }
+    }
+}
+
+#[async_trait]
+impl<LISTENER> KafkaConsumerListener for RecordDispatcher<LISTENER>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        match self.mode {
+            DispatchMode::Batch => self.listener.consume(records).await,
+            DispatchMode::PerRecord { max_concurrency, .. } => self.consume_each(records, max_concurrency).await
+        }
+    }
+
+    /// Batches are only limited by the listener when handed at once.
+    fn max_payload_size(&self) -> Option<usize> {
+        match self.mode {
+            DispatchMode::Batch => self.listener.max_payload_size(),
+            DispatchMode::PerRecord { .. } => None
+        }
+    }
+
+    fn max_record_size(&self) -> Option<usize> {
+        self.listener.max_record_size()
+    }
+
+    fn commits_offsets(&self) -> bool {
+        self.listener.commits_offsets()
+    }
+
+    fn empty_payload_size(&self, encoder: &PayloadEncoder) -> usize {
+        self.listener.empty_payload_size(encoder)
+    }
+
+    fn payload_size_increment(&self, encoder: &PayloadEncoder, record: &InFlightRecord, records_in_payload: usize) -> usize {
+        self.listener.payload_size_increment(encoder, record, records_in_payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::conf::DispatchMode;
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+    use crate::kafka::error::{FailureCause, KafkaConsumerError};
+
+    use super::RecordDispatcher;
+
+    /// Fails to consume the records found in `failures`, keeping track of how
+    /// many records were received per call and how many calls ran concurrently.
+    #[derive(Default)]
+    struct RecordingListener {
+        failures: Vec<(i64, KafkaConsumerError)>,
+        batch_sizes: Mutex<Vec<usize>>,
+        running: Mutex<(usize, usize)>
+    }
+
+    #[async_trait]
+    impl KafkaConsumerListener for RecordingListener {
+        async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+            self.batch_sizes.lock().unwrap().push(records.len());
+            {
+                let mut running = self.running.lock().unwrap();
+                running.0 += 1;
+                running.1 = running.1.max(running.0);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            self.running.lock().unwrap().0 -= 1;
+
+            let failure = self.failures.iter()
+                .find(|(offset, _)| records.iter().any(|record| record.offset == *offset));
+            match failure {
+                Some((_, cause)) => KafkaConsumerResult::Failed(cause.clone()),
+                None => KafkaConsumerResult::Succeeded
+            }
+        }
+    }
+
+    fn records(count: i64) -> Vec<InFlightRecord> {
+        (0..count).map(|offset| InFlightRecord { offset, ..InFlightRecord::create(None, None) }).collect()
+    }
+
+    #[tokio::test]
+    async fn should_consume_each_record_on_its_own_with_bounded_concurrency() {
+        let mode = DispatchMode::PerRecord { max_concurrency: 2, unwrap_payload: false };
+        let dispatcher = RecordDispatcher::create(mode, RecordingListener::default());
+
+        let result = dispatcher.consume(&records(5)).await;
+        assert_eq!(KafkaConsumerResult::Succeeded, result);
+        assert_eq!(vec!(1, 1, 1, 1, 1), *dispatcher.listener.batch_sizes.lock().unwrap());
+        assert_eq!(2, dispatcher.listener.running.lock().unwrap().1);
+    }
+
+    #[tokio::test]
+    async fn should_fail_the_batch_when_any_record_fails() {
+        let invalid = KafkaConsumerError::InvalidPayload(FailureCause::new("Invalid payload".to_string()));
+        let throttled = KafkaConsumerError::Throttled(FailureCause::new("Rate exceeded".to_string()));
+        let listener = RecordingListener {
+            failures: vec!((1, invalid), (3, throttled.clone())),
+            ..RecordingListener::default()
+        };
+        let mode = DispatchMode::PerRecord { max_concurrency: 5, unwrap_payload: false };
+        let dispatcher = RecordDispatcher::create(mode, listener);
+
+        let result = dispatcher.consume(&records(5)).await;
+        assert_eq!(KafkaConsumerResult::Failed(throttled), result);
+    }
+
+    #[tokio::test]
+    async fn should_only_dead_letter_the_records_that_failed() {
+        let invalid = KafkaConsumerError::InvalidPayload(FailureCause::new("Invalid payload".to_string()));
+        let listener = RecordingListener {
+            failures: vec!((1, invalid.clone()), (3, invalid)),
+            ..RecordingListener::default()
+        };
+        let mode = DispatchMode::PerRecord { max_concurrency: 5, unwrap_payload: false };
+        let dispatcher = RecordDispatcher::create(mode, listener);
+
+        let result = dispatcher.consume(&records(5)).await;
+        let mut failed_records = match result {
+            KafkaConsumerResult::Failed(cause) => cause.cause().records().unwrap().to_vec(),
+            _ => panic!("Expected the batch to fail")
+        };
+        failed_records.sort();
+        assert_eq!(vec!((String::new(), 0, 1), (String::new(), 0, 3)), failed_records);
+        assert_eq!(None, dispatcher.max_payload_size());
+    }
+}
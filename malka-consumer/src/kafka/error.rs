@@ -0,0 +1,229 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+type SharedError = Arc<dyn Error + Send + Sync>;
+
+/// Identifies a record by its topic, partition and offset.
+pub type RecordCoordinates = (String, i32, i64);
+
+/// Describes why records couldn't be consumed. Each variant
+/// determines how the subscriber should handle the failure.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum KafkaConsumerError {
+    /// Messages couldn't be polled from Kafka.
+    #[error(transparent)]
+    Poll(FailureCause),
+    /// The listener couldn't be reached (e.g. network or transient service failures).
+    #[error(transparent)]
+    Network(FailureCause),
+    /// The listener is rejecting requests due to rate limits.
+    #[error(transparent)]
+    Throttled(FailureCause),
+    /// The payload is bigger than what the listener accepts.
+    #[error(transparent)]
+    PayloadTooLarge(FailureCause),
+    /// The listener can't make sense of the payload.
+    #[error(transparent)]
+    InvalidPayload(FailureCause),
+    /// The listener target doesn't exist.
+    #[error(transparent)]
+    NotFound(FailureCause),
+    /// Malka isn't allowed to reach the listener target.
+    #[error(transparent)]
+    PermissionDenied(FailureCause)
+}
+
+/// How the subscriber should react to a `KafkaConsumerError`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureHandling {
+    /// Rolls back the transaction, so the records will be consumed again.
+    Rollback,
+    /// Pauses the affected partitions for a while, then rolls back the transaction.
+    Backoff,
+    /// Sends the records to the dead-letter topic, if any, and moves on.
+    DeadLetter,
+    /// Stops consuming, as retrying won't fix the problem.
+    Halt
+}
+
+impl KafkaConsumerError {
+
+    /// Whether consuming the very same records again might succeed.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self.handling(), FailureHandling::Rollback | FailureHandling::Backoff)
+    }
+
+    pub fn handling(&self) -> FailureHandling {
+        match self {
+            KafkaConsumerError::Poll(_) | KafkaConsumerError::Network(_) => FailureHandling::Rollback,
+            KafkaConsumerError::Throttled(_) => FailureHandling::Backoff,
+            KafkaConsumerError::PayloadTooLarge(_) | KafkaConsumerError::InvalidPayload(_) => FailureHandling::DeadLetter,
+            KafkaConsumerError::NotFound(_) | KafkaConsumerError::PermissionDenied(_) => FailureHandling::Halt
+        }
+    }
+
+    /// Picks which failure should be reported when records failed for different reasons.
+    /// Retrying is preferred over dead-lettering, as the failure handling affects the whole
+    /// batch, including records that were successfully consumed. When every failure should
+    /// be dead-lettered, the reported one affects all of the records the others did.
+    pub fn prevailing<I>(failures: I) -> Option<KafkaConsumerError>
+        where I: IntoIterator<Item = KafkaConsumerError> {
+        let failures: Vec<KafkaConsumerError> = failures.into_iter().collect();
+        let prevailing = failures.iter().min_by_key(|failure| match failure.handling() {
+            FailureHandling::Halt => 0,
+            FailureHandling::Backoff => 1,
+            FailureHandling::Rollback => 2,
+            FailureHandling::DeadLetter => 3
+        })?;
+        if prevailing.handling() != FailureHandling::DeadLetter {
+            return Some(prevailing.clone())
+        }
+
+        let mut records = Vec::new();
+        for failure in &failures {
+            match failure.cause().records() {
+                Some(affected) => records.extend_from_slice(affected),
+                None => return Some(failure.clone())
+            }
+        }
+        Some(prevailing.clone().affecting(records))
+    }
+
+    /// The cause of this failure.
+    pub fn cause(&self) -> &FailureCause {
+        match self {
+            KafkaConsumerError::Poll(cause)
+            | KafkaConsumerError::Network(cause)
+            | KafkaConsumerError::Throttled(cause)
+            | KafkaConsumerError::PayloadTooLarge(cause)
+            | KafkaConsumerError::InvalidPayload(cause)
+            | KafkaConsumerError::NotFound(cause)
+            | KafkaConsumerError::PermissionDenied(cause) => cause
+        }
+    }
+
+    /// This failure, narrowed down to the given records.
+    pub fn affecting(self, records: Vec<RecordCoordinates>) -> Self {
+        let affecting = |cause: FailureCause| FailureCause { records: Some(records), ..cause };
+        match self {
+            KafkaConsumerError::Poll(cause) => KafkaConsumerError::Poll(affecting(cause)),
+            KafkaConsumerError::Network(cause) => KafkaConsumerError::Network(affecting(cause)),
+            KafkaConsumerError::Throttled(cause) => KafkaConsumerError::Throttled(affecting(cause)),
+            KafkaConsumerError::PayloadTooLarge(cause) => KafkaConsumerError::PayloadTooLarge(affecting(cause)),
+            KafkaConsumerError::InvalidPayload(cause) => KafkaConsumerError::InvalidPayload(affecting(cause)),
+            KafkaConsumerError::NotFound(cause) => KafkaConsumerError::NotFound(affecting(cause)),
+            KafkaConsumerError::PermissionDenied(cause) => KafkaConsumerError::PermissionDenied(affecting(cause))
+        }
+    }
+
+    /// A short identifier of this failure, suitable to be used as a metric label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            KafkaConsumerError::Poll(_) => "poll",
+            KafkaConsumerError::Network(_) => "network",
+            KafkaConsumerError::Throttled(_) => "throttled",
+            KafkaConsumerError::PayloadTooLarge(_) => "payload_too_large",
+            KafkaConsumerError::InvalidPayload(_) => "invalid_payload",
+            KafkaConsumerError::NotFound(_) => "not_found",
+            KafkaConsumerError::PermissionDenied(_) => "permission_denied"
+        }
+    }
+}
+
+/// A failure description, along with the error that originated it.
+#[derive(Clone)]
+pub struct FailureCause {
+    message: String,
+    source: Option<SharedError>,
+    /// The records that failed, when only some records of the batch did.
+    records: Option<Vec<RecordCoordinates>>
+}
+
+impl FailureCause {
+    pub fn new(message: String) -> Self {
+        FailureCause { message, source: None, records: None }
+    }
+
+    pub fn with_source<E>(message: String, source: E) -> Self
+        where E: Error + Send + Sync + 'static {
+        FailureCause { message, source: Some(Arc::new(source)), records: None }
+    }
+
+    /// The records that failed, or `None` if the whole batch did.
+    pub fn records(&self) -> Option<&[RecordCoordinates]> {
+        self.records.as_deref()
+    }
+}
+
+impl fmt::Display for FailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl fmt::Debug for FailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{} (caused by {:?})", &self.message, source),
+            None => f.write_str(&self.message)
+        }
+    }
+}
+
+impl Error for FailureCause {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_ref().map(|source| source.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+/// Failures are considered equal when they describe the same problem,
+/// regardless of the error that originated them.
+impl PartialEq for FailureCause {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::io;
+
+    use super::{FailureCause, FailureHandling, KafkaConsumerError};
+
+    #[test]
+    fn should_preserve_the_source_error() {
+        let source = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset");
+        let failure = KafkaConsumerError::Network(FailureCause::with_source("Failed to invoke".to_string(), source));
+
+        assert_eq!("Failed to invoke", failure.to_string());
+        assert_eq!("connection reset", failure.source().unwrap().to_string());
+    }
+
+    #[test]
+    fn should_only_retry_transient_failures() {
+        let cause = FailureCause::new("failure".to_string());
+
+        assert!(KafkaConsumerError::Network(cause.clone()).is_retriable());
+        assert!(KafkaConsumerError::Throttled(cause.clone()).is_retriable());
+        assert!(!KafkaConsumerError::PayloadTooLarge(cause.clone()).is_retriable());
+        assert_eq!(FailureHandling::Halt, KafkaConsumerError::PermissionDenied(cause).handling());
+    }
+
+    #[test]
+    fn should_dead_letter_every_record_affected_by_the_prevailing_failures() {
+        let invalid = |offset: i64| KafkaConsumerError::InvalidPayload(FailureCause::new("invalid".to_string()))
+            .affecting(vec!(("user.delete".to_string(), 0, offset)));
+
+        let prevailing = KafkaConsumerError::prevailing(vec!(invalid(1), invalid(3))).unwrap();
+        let expected_records = [("user.delete".to_string(), 0, 1), ("user.delete".to_string(), 0, 3)];
+        assert_eq!(Some(&expected_records[..]), prevailing.cause().records());
+
+        let whole_batch = KafkaConsumerError::PayloadTooLarge(FailureCause::new("too large".to_string()));
+        let prevailing = KafkaConsumerError::prevailing(vec!(invalid(1), whole_batch)).unwrap();
+        assert_eq!(None, prevailing.cause().records());
+    }
+}
@@ -0,0 +1,206 @@
+use async_trait::async_trait;
+use futures::FutureExt;
+use log::warn;
+
+use crate::conf::CommitPolicy;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::KafkaConsumerError;
+use crate::kafka::payload::PayloadEncoder;
+
+/// A `KafkaConsumerListener` that hands every batch to all of its listeners,
+/// so a single consumer can feed many target functions. Whether the batch is
+/// considered consumed is decided by the `CommitPolicy`. Retrying a batch hands
+/// it again to every listener, including the ones that already consumed it.
+///
+/// Listeners are named after their target. Records with a `route` are only handed
+/// to the listener it names, and listeners left without records aren't called.
+/// As routed records are consumed by a single listener, routed batches are only
+/// considered consumed once every listener succeeded, whatever the `CommitPolicy`.
+/// Failures of listeners handed routed records only affect those records.
+///
+/// Payloads are sized after the listener measuring them the biggest, so batches fit
+/// every listener even when they encode payloads differently.
+pub struct FanOutListener<LISTENER>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync
+{
+    commit_policy: CommitPolicy,
+    listeners: Vec<(String, LISTENER)>
+}
+
+impl<LISTENER> FanOutListener<LISTENER>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync {
+
+    pub fn create(commit_policy: CommitPolicy, listeners: Vec<(String, LISTENER)>) -> Self {
+        FanOutListener { commit_policy, listeners }
+    }
+}
+
+/// The records that should be handed to the `target` listener, or `None` if all of them should.
+fn records_routed_to(target: &str, records: &[InFlightRecord]) -> Option<Vec<InFlightRecord>> {
+    if records.iter().all(|record| record.route.is_none()) {
+        return None
+    }
+
+    Some(records.iter()
+        .filter(|record| record.route.as_deref().is_none_or(|route| route == target))
+        .cloned()
+        .collect())
+}
+
+#[async_trait]
+impl<LISTENER> KafkaConsumerListener for FanOutListener<LISTENER>
+    where LISTENER: KafkaConsumerListener + std::marker::Sync {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let routed_records: Vec<Option<Vec<InFlightRecord>>> = self.listeners.iter()
+            .map(|(target, _)| records_routed_to(target, records))
+            .collect();
+        let is_routed = routed_records.iter().any(Option::is_some);
+
+        let mut invocations = Vec::with_capacity(self.listeners.len());
+        for ((_, listener), routed_records) in self.listeners.iter().zip(&routed_records) {
+            let records = routed_records.as_deref().unwrap_or(records);
+            if !records.is_empty() {
+                invocations.push(listener.consume(records).map(move |result| (routed_records, result)));
+            }
+        }
+
+        let results = futures::future::join_all(invocations).await;
+        let succeeded = results.iter().filter(|(_, result)| *result == KafkaConsumerResult::Succeeded).count();
+        let failures: Vec<KafkaConsumerError> = results.into_iter()
+            .filter_map(|(routed_records, result)| match (routed_records, result) {
+                (Some(routed_records), KafkaConsumerResult::Failed(cause)) if cause.cause().records().is_none() =>
+                    Some(cause.affecting(routed_records.iter().map(InFlightRecord::coordinates).collect())),
+                (_, KafkaConsumerResult::Failed(cause)) => Some(cause),
+                _ => None
+            })
+            .collect();
+
+        if failures.is_empty() {
+            return KafkaConsumerResult::Succeeded
+        }
+
+        match self.commit_policy {
+            CommitPolicy::AnySucceeded if succeeded > 0 && !is_routed => {
+                for failure in &failures {
+                    warn!("Moving on, as records were consumed by other listener(s). Listener failed: {}.", failure);
+                }
+                KafkaConsumerResult::Succeeded
+            },
+            CommitPolicy::AnySucceeded | CommitPolicy::AllSucceeded =>
+                KafkaConsumerResult::Failed(KafkaConsumerError::prevailing(failures).unwrap())
+        }
+    }
+
+    fn max_payload_size(&self) -> Option<usize> {
+        self.listeners.iter()
+            .filter_map(|(_, listener)| listener.max_payload_size())
+            .min()
+    }
+
+    fn max_record_size(&self) -> Option<usize> {
+        self.listeners.iter()
+            .filter_map(|(_, listener)| listener.max_record_size())
+            .min()
+    }
+
+    fn commits_offsets(&self) -> bool {
+        self.listeners.iter().all(|(_, listener)| listener.commits_offsets())
+    }
+
+    fn empty_payload_size(&self, encoder: &PayloadEncoder) -> usize {
+        self.listeners.iter()
+            .map(|(_, listener)| listener.empty_payload_size(encoder))
+            .max()
+            .unwrap_or_else(|| encoder.empty_payload_size())
+    }
+
+    fn payload_size_increment(&self, encoder: &PayloadEncoder, record: &InFlightRecord, records_in_payload: usize) -> usize {
+        self.listeners.iter()
+            .map(|(_, listener)| listener.payload_size_increment(encoder, record, records_in_payload))
+            .max()
+            .unwrap_or_else(|| encoder.payload_size_increment(record, records_in_payload))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+
+    use crate::conf::CommitPolicy;
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+    use crate::kafka::error::{FailureCause, KafkaConsumerError};
+
+    use super::FanOutListener;
+
+    struct FixedResultListener(KafkaConsumerResult);
+
+    #[async_trait]
+    impl KafkaConsumerListener for FixedResultListener {
+        async fn consume(&self, _records: &[InFlightRecord]) -> KafkaConsumerResult {
+            self.0.clone()
+        }
+    }
+
+    fn listeners() -> Vec<(String, FixedResultListener)> {
+        let failure = KafkaConsumerError::Network(FailureCause::new("Connection reset".to_string()));
+        vec!(
+            ("user_deleted".to_string(), FixedResultListener(KafkaConsumerResult::Succeeded)),
+            ("user_audit".to_string(), FixedResultListener(KafkaConsumerResult::Failed(failure)))
+        )
+    }
+
+    #[tokio::test]
+    async fn should_only_succeed_when_all_listeners_succeed() {
+        let fan_out = FanOutListener::create(CommitPolicy::AllSucceeded, listeners());
+        let result = fan_out.consume(&[InFlightRecord::create(None, None)]).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn should_only_hand_routed_records_to_their_target() {
+        let fan_out = FanOutListener::create(CommitPolicy::AllSucceeded, listeners());
+        let record = InFlightRecord { route: Some("user_deleted".to_string()), ..InFlightRecord::create(None, None) };
+        let result = fan_out.consume(&[record]).await;
+        assert_eq!(KafkaConsumerResult::Succeeded, result);
+    }
+
+    #[tokio::test]
+    async fn should_only_succeed_when_all_routes_succeed() {
+        let fan_out = FanOutListener::create(CommitPolicy::AnySucceeded, listeners());
+        let records = [
+            InFlightRecord { route: Some("user_deleted".to_string()), ..InFlightRecord::create(None, None) },
+            InFlightRecord { route: Some("user_audit".to_string()), ..InFlightRecord::create(None, None) }
+        ];
+        let result = fan_out.consume(&records).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn should_only_dead_letter_the_records_of_failed_routes() {
+        let invalid = KafkaConsumerError::InvalidPayload(FailureCause::new("Invalid payload".to_string()));
+        let listeners = vec!(
+            ("user_deleted".to_string(), FixedResultListener(KafkaConsumerResult::Succeeded)),
+            ("user_audit".to_string(), FixedResultListener(KafkaConsumerResult::Failed(invalid)))
+        );
+        let fan_out = FanOutListener::create(CommitPolicy::AllSucceeded, listeners);
+        let routed_to = |target: &str, offset| InFlightRecord {
+            topic: "user.delete".to_string(), offset, route: Some(target.to_string()), ..InFlightRecord::create(None, None)
+        };
+        let records = [routed_to("user_deleted", 0), routed_to("user_audit", 1), routed_to("user_deleted", 2), routed_to("user_audit", 3)];
+
+        match fan_out.consume(&records).await {
+            KafkaConsumerResult::Failed(failure) => assert_eq!(
+                Some(&[("user.delete".to_string(), 0, 1), ("user.delete".to_string(), 0, 3)][..]),
+                failure.cause().records()),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+
+    #[tokio::test]
+    async fn should_succeed_when_any_listener_succeeds() {
+        let fan_out = FanOutListener::create(CommitPolicy::AnySucceeded, listeners());
+        let result = fan_out.consume(&[InFlightRecord::create(None, None)]).await;
+        assert_eq!(KafkaConsumerResult::Succeeded, result);
+    }
+}
@@ -0,0 +1,161 @@
+use regex::Regex;
+use serde_json::Value;
+
+use crate::conf::RecordFilterConfig;
+use crate::error::{KnownHandledErrors, Result};
+use crate::kafka::consumer::InFlightRecord;
+
+pub const METRIC_FILTERED_RECORDS: &str = "filtered_records";
+
+/// Decides which records should be delivered, based on the subscription `filters`.
+/// Records are delivered when they match any of the filters, or when there are none.
+pub struct RecordFilter {
+    filters: Vec<RecordMatcher>
+}
+
+/// Checks whether records meet the conditions of a `RecordFilterConfig`.
+pub struct RecordMatcher {
+    headers: Vec<(String, Vec<String>)>,
+    key_pattern: Option<Regex>,
+    /// JSON pointers into the value, along with their accepted values.
+    value: Vec<(String, Vec<Value>)>
+}
+
+impl RecordFilter {
+
+    pub fn create(configs: &[RecordFilterConfig]) -> Result<Self> {
+        let filters = configs.iter()
+            .map(RecordMatcher::create)
+            .collect::<Result<Vec<RecordMatcher>>>()?;
+        Ok(RecordFilter { filters })
+    }
+
+    pub fn accepts(&self, record: &InFlightRecord) -> bool {
+        if self.filters.is_empty() {
+            return true
+        }
+
+        let value = value_of(record, &self.filters);
+        self.filters.iter().any(|filter| filter.matches(record, value.as_ref()))
+    }
+}
+
+/// Parses the record value as JSON, if any of the `matchers` looks into it.
+pub fn value_of(record: &InFlightRecord, matchers: &[RecordMatcher]) -> Option<Value> {
+    match matchers.iter().any(|matcher| !matcher.value.is_empty()) {
+        true => record.value.as_deref().and_then(|value| serde_json::from_slice(value).ok()),
+        false => None
+    }
+}
+
+impl RecordMatcher {
+
+    pub fn create(config: &RecordFilterConfig) -> Result<Self> {
+        let key_pattern = config.key_pattern.as_deref()
+            .map(|pattern| Regex::new(pattern).map_err(|cause| KnownHandledErrors::InvalidConfiguration(
+                format!("invalid key pattern {}: {}", pattern, cause))))
+            .transpose()?;
+        let value = config.value.iter()
+            .map(|(path, accepted)| Ok((json_pointer_of(path)?, accepted.clone())))
+            .collect::<Result<Vec<(String, Vec<Value>)>>>()?;
+
+        Ok(RecordMatcher {
+            headers: config.headers.iter().map(|(name, accepted)| (name.clone(), accepted.clone())).collect(),
+            key_pattern, value
+        })
+    }
+
+    /// Whether `record`, whose value was parsed by `value_of`, meets every condition.
+    pub fn matches(&self, record: &InFlightRecord, value: Option<&Value>) -> bool {
+        let headers_match = self.headers.iter().all(|(name, accepted)| record.headers.iter()
+            .any(|(header, header_value)| header == name
+                && accepted.iter().any(|accepted| accepted.as_bytes() == header_value.as_slice())));
+        let key_matches = self.key_pattern.as_ref().is_none_or(|pattern| record.key_as_str()
+            .is_some_and(|key| pattern.is_match(&key)));
+        let value_matches = self.value.iter().all(|(pointer, accepted)| value
+            .and_then(|value| value.pointer(pointer))
+            .is_some_and(|found| accepted.contains(found)));
+
+        headers_match && key_matches && value_matches
+    }
+}
+
+/// Converts a JSON path, like `$.user.roles[0]`, into a JSON pointer (`/user/roles/0`).
+fn json_pointer_of(path: &str) -> Result<String> {
+    let invalid_path = || KnownHandledErrors::InvalidConfiguration(
+        format!("invalid JSON path {}, expected something like $.user.roles[0]", path));
+
+    let segments = path.strip_prefix('$').ok_or_else(invalid_path)?
+        .replace('[', ".")
+        .replace(']', "");
+    let mut segments = segments.split('.');
+    if segments.next() != Some("") {
+        return Err(invalid_path())
+    }
+
+    let mut pointer = String::new();
+    for segment in segments {
+        if segment.is_empty() {
+            return Err(invalid_path())
+        }
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+    Ok(pointer)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::conf::RecordFilterConfig;
+    use crate::kafka::consumer::InFlightRecord;
+
+    use super::{json_pointer_of, RecordFilter};
+
+    fn filter(json: &str) -> RecordFilter {
+        let configs: Vec<RecordFilterConfig> = serde_json::from_str(json).unwrap();
+        RecordFilter::create(&configs).unwrap()
+    }
+
+    fn record(key: &str, value: &str, headers: &[(&str, &str)]) -> InFlightRecord {
+        InFlightRecord {
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect(),
+            ..InFlightRecord::create(Some(key.as_bytes()), Some(value.as_bytes()))
+        }
+    }
+
+    #[test]
+    fn should_only_accept_records_meeting_every_condition_of_a_filter() {
+        let filter = filter(r#"[{
+            "headers": { "source": ["users", "admin"] },
+            "key_pattern": "^user-[0-9]+$",
+            "value": { "$.eventType": ["DELETED"], "$.user.roles[0]": ["admin"] }
+        }]"#);
+
+        let value = r#"{"eventType":"DELETED","user":{"roles":["admin"]}}"#;
+        assert!(filter.accepts(&record("user-1", value, &[("source", "users")])));
+        assert!(!filter.accepts(&record("user-1", value, &[("source", "billing")])));
+        assert!(!filter.accepts(&record("user-1", value, &[])));
+        assert!(!filter.accepts(&record("group-1", value, &[("source", "users")])));
+        assert!(!filter.accepts(&record("user-1", r#"{"eventType":"CREATED"}"#, &[("source", "users")])));
+        assert!(!filter.accepts(&record("user-1", "not json", &[("source", "users")])));
+    }
+
+    #[test]
+    fn should_accept_records_matching_any_filter() {
+        let filter = filter(r#"[{ "value": { "$.eventType": ["DELETED"] } }, { "key_pattern": "^admin-" }]"#);
+        assert!(filter.accepts(&record("user-1", r#"{"eventType":"DELETED"}"#, &[])));
+        assert!(filter.accepts(&record("admin-1", r#"{"eventType":"CREATED"}"#, &[])));
+        assert!(!filter.accepts(&record("user-1", r#"{"eventType":"CREATED"}"#, &[])));
+
+        assert!(RecordFilter::create(&[]).unwrap().accepts(&record("user-1", "", &[])));
+    }
+
+    #[test]
+    fn should_convert_json_paths_into_pointers() {
+        assert_eq!("/user/roles/0", json_pointer_of("$.user.roles[0]").unwrap());
+        assert_eq!("/a~1b", json_pointer_of("$.a/b").unwrap());
+        assert_eq!("", json_pointer_of("$").unwrap());
+        assert!(json_pointer_of("user.roles").is_err());
+        assert!(json_pointer_of("$..user").is_err());
+    }
+}
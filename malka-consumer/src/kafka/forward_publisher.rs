@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::error;
+use rdkafka::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use crate::conf::KafkaForwardConfig;
+use crate::error::Result;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::OVERSIZED_VALUE_CONTENT_TYPE;
+
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Tells records forwarded with their `OversizedValue` descriptor apart.
+const HEADER_CONTENT_TYPE: &str = "malka.content-type";
+
+/// A `KafkaConsumerListener` implementation that produces records to another topic,
+/// possibly on another cluster. Records are considered consumed once all of them
+/// were acknowledged by the target brokers, so they're forwarded at least once.
+///
+/// Records whose value was too big are forwarded with their `OversizedValue` descriptor
+/// as value, and a `malka.content-type` header, so they aren't taken for tombstones.
+pub struct ForwardingKafkaConsumerListener {
+    producer: FutureProducer,
+    topic: String,
+    key_header: Option<String>
+}
+
+impl ForwardingKafkaConsumerListener {
+    pub fn create(config: KafkaForwardConfig, mut producer_config: ClientConfig) -> Result<Self> {
+        for (key, value) in &config.producer_configuration {
+            producer_config.set(key, value);
+        }
+        let producer: FutureProducer = producer_config.create()?;
+        Ok(ForwardingKafkaConsumerListener { producer, topic: config.topic, key_header: config.key_header })
+    }
+
+    /// The key records are forwarded with.
+    fn key_of<'a>(&self, record: &'a InFlightRecord) -> Option<&'a [u8]> {
+        let rekeyed = self.key_header.as_ref().and_then(|key_header| {
+            record.headers.iter()
+                .find(|(name, _)| name == key_header)
+                .map(|(_, value)| value.as_slice())
+        });
+        rekeyed.or(record.key.as_deref())
+    }
+
+    /// The value records are forwarded with.
+    fn value_of(record: &InFlightRecord) -> Option<Cow<'_, [u8]>> {
+        match &record.oversized_value {
+            Some(oversized_value) => Some(Cow::Owned(
+                serde_json::to_vec(oversized_value).expect("Failed to serialize oversized value"))),
+            None => record.value.as_deref().map(Cow::Borrowed)
+        }
+    }
+
+    async fn forward(&self, record: &InFlightRecord) -> std::result::Result<(), KafkaError> {
+        let mut headers = OwnedHeaders::new();
+        for (name, value) in &record.headers {
+            headers = headers.add(name, value);
+        }
+        if record.oversized_value.is_some() {
+            headers = headers.add(HEADER_CONTENT_TYPE, OVERSIZED_VALUE_CONTENT_TYPE);
+        }
+
+        let value = Self::value_of(record);
+        let mut forwarded: FutureRecord<[u8], [u8]> = FutureRecord::to(&self.topic).headers(headers);
+        if let Some(key) = self.key_of(record) {
+            forwarded = forwarded.key(key);
+        }
+        if let Some(value) = &value {
+            forwarded = forwarded.payload(value.as_ref());
+        }
+        if let Some(timestamp) = record.timestamp.to_millis() {
+            forwarded = forwarded.timestamp(timestamp);
+        }
+
+        self.producer.send(forwarded, QUEUE_TIMEOUT).await
+            .map(|_| ())
+            .map_err(|(cause, _)| cause)
+    }
+
+    fn classify_failure(&self, cause: KafkaError) -> KafkaConsumerError {
+        let classify: fn(FailureCause) -> KafkaConsumerError = match cause.rdkafka_error_code() {
+            Some(RDKafkaErrorCode::QueueFull) => KafkaConsumerError::Throttled,
+            Some(RDKafkaErrorCode::MessageSizeTooLarge) => KafkaConsumerError::PayloadTooLarge,
+            Some(RDKafkaErrorCode::InvalidMessage) => KafkaConsumerError::InvalidPayload,
+            Some(RDKafkaErrorCode::UnknownTopic) => KafkaConsumerError::NotFound,
+            Some(RDKafkaErrorCode::TopicAuthorizationFailed) => KafkaConsumerError::PermissionDenied,
+            _ => KafkaConsumerError::Network
+        };
+        classify(FailureCause::with_source(format!("Failed to forward records to {}", &self.topic), cause))
+    }
+}
+
+#[async_trait]
+impl KafkaConsumerListener for ForwardingKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let mut deliveries = Vec::with_capacity(records.len());
+        for record in records {
+            deliveries.push(self.forward(record));
+        }
+
+        let failures = futures::future::join_all(deliveries).await.into_iter()
+            .zip(records)
+            .filter_map(|(delivery, record)| delivery.err().map(|cause| (cause, record)))
+            .map(|(cause, record)| {
+                error!("Failed to forward record {}-{}@{} to {}: {}", &record.topic, record.partition, record.offset, &self.topic, cause);
+                self.classify_failure(cause).affecting(vec!(record.coordinates()))
+            });
+
+        match KafkaConsumerError::prevailing(failures) {
+            Some(cause) => KafkaConsumerResult::Failed(cause),
+            None => KafkaConsumerResult::Succeeded
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rdkafka::ClientConfig;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+
+    use crate::conf::KafkaForwardConfig;
+    use crate::kafka::consumer::{InFlightRecord, OversizedValue};
+    use crate::kafka::error::KafkaConsumerError;
+
+    use super::ForwardingKafkaConsumerListener;
+
+    fn listener(key_header: Option<&str>) -> ForwardingKafkaConsumerListener {
+        let config = KafkaForwardConfig {
+            topic: "user.delete.mirror".to_string(),
+            producer_configuration: HashMap::from([("bootstrap.servers".to_string(), "127.0.0.1:9092".to_string())]),
+            key_header: key_header.map(str::to_string)
+        };
+        ForwardingKafkaConsumerListener::create(config, ClientConfig::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_rekey_records_with_the_configured_header() {
+        let mut record = InFlightRecord::create(Some("k".as_bytes()), Some("v".as_bytes()));
+        record.headers.push(("user-id".to_string(), b"42".to_vec()));
+
+        assert_eq!(Some("42".as_bytes()), listener(Some("user-id")).key_of(&record));
+        assert_eq!(Some("k".as_bytes()), listener(Some("tenant-id")).key_of(&record));
+        assert_eq!(Some("k".as_bytes()), listener(None).key_of(&record));
+    }
+
+    #[test]
+    fn should_forward_oversized_values_as_their_descriptor() {
+        let record = InFlightRecord::create(Some("k".as_bytes()), Some("v".as_bytes()));
+        assert_eq!(Some("v".as_bytes()), ForwardingKafkaConsumerListener::value_of(&record).as_deref());
+
+        let oversized_value = OversizedValue {
+            size: 7340032, key_size: None, topic: "user.delete".to_string(), partition: 0, offset: 1, location: None
+        };
+        let record = InFlightRecord { oversized_value: Some(oversized_value), ..InFlightRecord::create(Some("k".as_bytes()), None) };
+        let expected = r#"{"size":7340032,"topic":"user.delete","partition":0,"offset":1}"#;
+        assert_eq!(Some(expected.as_bytes()), ForwardingKafkaConsumerListener::value_of(&record).as_deref());
+    }
+
+    #[tokio::test]
+    async fn should_classify_delivery_failures() {
+        let listener = listener(None);
+
+        let failure = listener.classify_failure(KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull));
+        assert!(matches!(failure, KafkaConsumerError::Throttled(_)));
+        let failure = listener.classify_failure(KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge));
+        assert!(matches!(failure, KafkaConsumerError::PayloadTooLarge(_)));
+        let failure = listener.classify_failure(KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut));
+        assert!(failure.is_retriable());
+    }
+}
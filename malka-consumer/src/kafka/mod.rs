@@ -1,3 +1,13 @@
 pub mod subscriber;
 pub mod consumer;
 pub mod defaults;
+pub mod dead_letter;
+pub mod dispatcher;
+pub mod error;
+pub mod fan_out;
+pub mod filter;
+pub mod forward_publisher;
+pub mod oversized;
+pub mod payload;
+pub mod router;
+pub mod transform;
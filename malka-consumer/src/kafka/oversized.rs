@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::PathBuf;
+
+use log::warn;
+use rdkafka::ClientConfig;
+
+use crate::conf::OversizedRecordPolicy;
+use crate::error::Result;
+use crate::kafka::consumer::{InFlightRecord, OversizedValue};
+use crate::kafka::dead_letter::DeadLetterPublisher;
+use crate::metrics;
+
+pub const METRIC_OVERSIZED_RECORDS: &str = "oversized_records";
+
+/// Applies the `OversizedRecordPolicy` to records that are too big to be delivered.
+pub struct OversizedRecordHandler {
+    policy: OversizedRecordPolicy,
+    value_store: Option<LocalValueStore>,
+    dead_letter: Option<DeadLetterPublisher>
+}
+
+impl OversizedRecordHandler {
+    pub fn create(policy: &OversizedRecordPolicy, producer_config: ClientConfig) -> Result<Self> {
+        let value_store = match policy {
+            OversizedRecordPolicy::Offload { directory } => Some(LocalValueStore::create(directory)?),
+            _ => None
+        };
+
+        let dead_letter = match policy {
+            OversizedRecordPolicy::DeadLetter { topic } => Some(DeadLetterPublisher::create(topic.clone(), producer_config)?),
+            _ => None
+        };
+
+        Ok(OversizedRecordHandler {
+            policy: policy.clone(),
+            value_store, dead_letter
+        })
+    }
+
+    /// Handles an oversized record. Returns the record that should be delivered
+    /// in its place, or `None` if it was fully handled here. Records left without
+    /// their value are checked against `fits`: if they're still too big, their key
+    /// is dropped as well, and they're skipped if even that isn't enough.
+    pub async fn handle(&self, mut record: InFlightRecord, fits: impl Fn(&InFlightRecord) -> bool) -> Result<Option<InFlightRecord>> {
+        let policy_name = self.policy_name();
+        warn!("Record {}-{}@{} is too big to be delivered. Applying '{}' policy.",
+              &record.topic, record.partition, record.offset, policy_name);
+        metrics::global().increment(METRIC_OVERSIZED_RECORDS, &[
+            ("topic", &record.topic), ("policy", policy_name)
+        ]);
+
+        match &self.policy {
+            OversizedRecordPolicy::MetadataOnly => {
+                record.oversized_value = Some(OversizedRecordHandler::describe_value(&record, None));
+                record.value = None;
+                Ok(OversizedRecordHandler::fit_without_key(record, fits))
+            },
+            OversizedRecordPolicy::Offload { .. } => {
+                let value_store = self.value_store.as_ref().expect("Value store not configured");
+                let location = value_store.store(&record)?;
+                record.oversized_value = Some(OversizedRecordHandler::describe_value(&record, Some(location)));
+                record.value = None;
+                Ok(OversizedRecordHandler::fit_without_key(record, fits))
+            },
+            OversizedRecordPolicy::DeadLetter { .. } => {
+                let dead_letter = self.dead_letter.as_ref().expect("Dead-letter publisher not configured");
+                dead_letter.publish(&record).await?;
+                Ok(None)
+            },
+            OversizedRecordPolicy::Skip => Ok(None)
+        }
+    }
+
+    /// Drops the key of records that are still too big without their value.
+    /// Returns `None`, skipping the record, if that isn't enough.
+    fn fit_without_key(mut record: InFlightRecord, fits: impl Fn(&InFlightRecord) -> bool) -> Option<InFlightRecord> {
+        if fits(&record) {
+            return Some(record)
+        }
+
+        warn!("Record {}-{}@{} is still too big without its value. Dropping its key.",
+              &record.topic, record.partition, record.offset);
+        let key_size = record.key.take().map(|key| key.len());
+        if let Some(oversized_value) = record.oversized_value.as_mut() {
+            oversized_value.key_size = key_size;
+        }
+        if fits(&record) {
+            return Some(record)
+        }
+
+        warn!("Record {}-{}@{} is still too big without its key. Skipping it.",
+              &record.topic, record.partition, record.offset);
+        metrics::global().increment(METRIC_OVERSIZED_RECORDS, &[("topic", &record.topic), ("policy", "skip")]);
+        None
+    }
+
+    fn describe_value(record: &InFlightRecord, location: Option<String>) -> OversizedValue {
+        OversizedValue {
+            size: record.value.as_ref().map(Vec::len).unwrap_or(0),
+            key_size: None,
+            topic: record.topic.clone(),
+            partition: record.partition,
+            offset: record.offset,
+            location
+        }
+    }
+
+    fn policy_name(&self) -> &'static str {
+        match self.policy {
+            OversizedRecordPolicy::MetadataOnly => "metadata_only",
+            OversizedRecordPolicy::Offload { .. } => "offload",
+            OversizedRecordPolicy::DeadLetter { .. } => "dead_letter",
+            OversizedRecordPolicy::Skip => "skip"
+        }
+    }
+}
+
+/// A local stand-in for a blob store. Values are written as files
+/// named after the record coordinates, inside a given directory.
+pub struct LocalValueStore {
+    directory: PathBuf
+}
+
+impl LocalValueStore {
+    pub fn create(directory: &str) -> Result<Self> {
+        fs::create_dir_all(directory)?;
+        let directory = fs::canonicalize(directory)?;
+        Ok(LocalValueStore { directory })
+    }
+
+    /// Stores the record value and returns an URL pointing to it.
+    pub fn store(&self, record: &InFlightRecord) -> Result<String> {
+        let file_name = format!("{}-{}-{}", &record.topic, record.partition, record.offset);
+        let path = self.directory.join(file_name);
+        fs::write(&path, record.value.as_deref().unwrap_or_default())?;
+        Ok(format!("file://{}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use rdkafka::ClientConfig;
+
+    use crate::conf::OversizedRecordPolicy;
+    use crate::kafka::consumer::InFlightRecord;
+    use crate::kafka::oversized::{METRIC_OVERSIZED_RECORDS, OversizedRecordHandler};
+    use crate::metrics;
+
+    const VALUE: &str = "{\"hello\":\"world\"}";
+
+    fn create_record(topic: &str) -> InFlightRecord {
+        InFlightRecord {
+            topic: topic.to_string(),
+            partition: 3,
+            offset: 42,
+            ..InFlightRecord::create(Some("key".as_bytes()), Some(VALUE.as_bytes()))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_send_only_metadata_when_configured_to() {
+        let handler = OversizedRecordHandler::create(&OversizedRecordPolicy::MetadataOnly, ClientConfig::new()).unwrap();
+        let record = handler.handle(create_record("oversized.metadata"), |_| true).await.unwrap().unwrap();
+
+        assert_eq!(None, record.value);
+        let oversized_value = record.oversized_value.unwrap();
+        assert_eq!(VALUE.len(), oversized_value.size);
+        assert_eq!(42, oversized_value.offset);
+        assert_eq!(None, oversized_value.location);
+    }
+
+    #[tokio::test]
+    async fn should_drop_oversized_keys_or_skip_records_that_still_do_not_fit() {
+        let handler = OversizedRecordHandler::create(&OversizedRecordPolicy::MetadataOnly, ClientConfig::new()).unwrap();
+        let record = handler.handle(create_record("oversized.key"), |record| record.key.is_none()).await.unwrap().unwrap();
+        assert_eq!(None, record.key);
+        assert_eq!(Some("key".len()), record.oversized_value.unwrap().key_size);
+
+        let record = handler.handle(create_record("oversized.key.skip"), |_| false).await.unwrap();
+        assert!(record.is_none());
+        let labels = [("topic", "oversized.key.skip"), ("policy", "skip")];
+        assert_eq!(1, metrics::global().counter(METRIC_OVERSIZED_RECORDS, &labels));
+    }
+
+    #[tokio::test]
+    async fn should_offload_the_value_and_send_its_location() {
+        let directory = env::temp_dir().join("malka-oversized-test");
+        let policy = OversizedRecordPolicy::Offload { directory: directory.display().to_string() };
+        let handler = OversizedRecordHandler::create(&policy, ClientConfig::new()).unwrap();
+        let record = handler.handle(create_record("oversized.offload"), |_| true).await.unwrap().unwrap();
+
+        let location = record.oversized_value.unwrap().location.unwrap();
+        let path = location.strip_prefix("file://").unwrap();
+        assert_eq!(VALUE, fs::read_to_string(path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_skip_and_count_the_record() {
+        let handler = OversizedRecordHandler::create(&OversizedRecordPolicy::Skip, ClientConfig::new()).unwrap();
+        let record = handler.handle(create_record("oversized.skip"), |_| true).await.unwrap();
+
+        assert!(record.is_none());
+        let labels = [("topic", "oversized.skip"), ("policy", "skip")];
+        assert_eq!(1, metrics::global().counter(METRIC_OVERSIZED_RECORDS, &labels));
+    }
+}
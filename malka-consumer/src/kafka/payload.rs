@@ -0,0 +1,276 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use chrono::{TimeZone, Utc};
+use rdkafka::message::Timestamp;
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::conf::PayloadFormat;
+use crate::kafka::consumer::{EMPTY_PAYLOAD_SIZE, InFlightRecord, OversizedValue};
+
+const EVENT_SOURCE: &str = "SelfManagedKafka";
+const CLOUD_EVENTS_SPEC_VERSION: &str = "1.0";
+const CLOUD_EVENTS_TYPE: &str = "malka.kafka.record";
+pub const OVERSIZED_VALUE_CONTENT_TYPE: &str = "application/vnd.malka.oversized-value+json";
+
+/// Wraps records into the envelope expected by listeners, according to the `PayloadFormat`.
+#[derive(Clone, Debug)]
+pub struct PayloadEncoder {
+    format: PayloadFormat,
+    bootstrap_servers: String,
+    unwrap_single_record: bool
+}
+
+impl PayloadEncoder {
+    pub fn create(format: PayloadFormat, bootstrap_servers: String) -> Self {
+        PayloadEncoder { format, bootstrap_servers, unwrap_single_record: false }
+    }
+
+    /// Payloads holding a single record will have the record sent on its own,
+    /// rather than within an array. Doesn't apply to self-managed Kafka events.
+    pub fn unwrapping_single_records(self) -> Self {
+        PayloadEncoder { unwrap_single_record: true, ..self }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            PayloadFormat::CloudEvents if self.unwrap_single_record => "application/cloudevents+json",
+            PayloadFormat::CloudEvents => "application/cloudevents-batch+json",
+            PayloadFormat::Array | PayloadFormat::SelfManagedKafka => "application/json"
+        }
+    }
+
+    pub fn encode(&self, records: &[InFlightRecord]) -> Vec<u8> {
+        let serialized = match (self.format, records) {
+            (PayloadFormat::Array, [record]) if self.unwrap_single_record => serde_json::to_vec(record),
+            (PayloadFormat::CloudEvents, [record]) if self.unwrap_single_record => serde_json::to_vec(&CloudEvent::from(record)),
+            (PayloadFormat::Array, _) => serde_json::to_vec(records),
+            (PayloadFormat::SelfManagedKafka, _) => serde_json::to_vec(&self.as_kafka_event(records)),
+            (PayloadFormat::CloudEvents, _) => serde_json::to_vec(&records.iter().map(CloudEvent::from).collect::<Vec<_>>())
+        };
+        serialized.expect("Failed to serialize message")
+    }
+
+    /// Size, in bytes, of a payload without records.
+    pub fn empty_payload_size(&self) -> usize {
+        match self.format {
+            PayloadFormat::Array => EMPTY_PAYLOAD_SIZE,
+            PayloadFormat::SelfManagedKafka | PayloadFormat::CloudEvents => self.encode(&[]).len()
+        }
+    }
+
+    /// Number of bytes `record` adds to a payload that already holds `records_in_payload`
+    /// records. As self-managed Kafka events group records by partition, their increment
+    /// always accounts for a new group, making it an upper bound of the actual increment.
+    pub fn payload_size_increment(&self, record: &InFlightRecord, records_in_payload: usize) -> usize {
+        let separator = if records_in_payload > 0 { 1 } else { 0 };
+        match self.format {
+            PayloadFormat::Array => record.payload_size_increment(records_in_payload),
+            PayloadFormat::SelfManagedKafka => {
+                let group = serde_json::to_vec(&BTreeMap::from([(group_name_of(record), [(); 0])]))
+                    .expect("Failed to serialize message");
+                // group declaration, without its curly brackets, followed by the record
+                group.len() - 2 + separator + serialized_size_of(&KafkaEventRecord::from(record))
+            },
+            PayloadFormat::CloudEvents => separator + serialized_size_of(&CloudEvent::from(record))
+        }
+    }
+
+    fn as_kafka_event<'a>(&'a self, records: &'a [InFlightRecord]) -> KafkaEvent<'a> {
+        let mut grouped_records: BTreeMap<String, Vec<KafkaEventRecord>> = BTreeMap::new();
+        for record in records {
+            grouped_records.entry(group_name_of(record))
+                .or_default()
+                .push(KafkaEventRecord::from(record));
+        }
+
+        KafkaEvent {
+            event_source: EVENT_SOURCE,
+            bootstrap_servers: &self.bootstrap_servers,
+            records: grouped_records
+        }
+    }
+}
+
+fn group_name_of(record: &InFlightRecord) -> String {
+    format!("{}-{}", &record.topic, record.partition)
+}
+
+fn serialized_size_of<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).expect("Failed to serialize message").len()
+}
+
+/// Mirrors the event sent by AWS's self-managed Kafka event source.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KafkaEvent<'a> {
+    event_source: &'static str,
+    bootstrap_servers: &'a str,
+    records: BTreeMap<String, Vec<KafkaEventRecord<'a>>>
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KafkaEventRecord<'a> {
+    topic: &'a str,
+    partition: i32,
+    offset: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    headers: Vec<BTreeMap<&'a str, &'a [u8]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oversized_value: Option<&'a OversizedValue>
+}
+
+impl<'a> From<&'a InFlightRecord> for KafkaEventRecord<'a> {
+    fn from(record: &'a InFlightRecord) -> Self {
+        let (timestamp, timestamp_type) = match record.timestamp {
+            Timestamp::CreateTime(millis) => (Some(millis), Some("CREATE_TIME")),
+            Timestamp::LogAppendTime(millis) => (Some(millis), Some("LOG_APPEND_TIME")),
+            Timestamp::NotAvailable => (None, None)
+        };
+
+        KafkaEventRecord {
+            topic: &record.topic,
+            partition: record.partition,
+            offset: record.offset,
+            timestamp, timestamp_type,
+            key: record.key.as_ref().map(base64::encode),
+            value: record.value.as_ref().map(base64::encode),
+            headers: record.headers.iter()
+                .map(|(name, value)| BTreeMap::from([(name.as_str(), value.as_slice())]))
+                .collect(),
+            oversized_value: record.oversized_value.as_ref()
+        }
+    }
+}
+
+/// A CloudEvents (v1.0) representation of a record, as defined by its JSON format.
+/// Values are sent as JSON whenever possible, and as plain text otherwise.
+#[derive(Serialize)]
+struct CloudEvent<'a> {
+    specversion: &'static str,
+    id: String,
+    source: String,
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partitionkey: Option<Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    datacontenttype: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<CloudEventData<'a>>
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CloudEventData<'a> {
+    Json(&'a RawValue),
+    Text(Cow<'a, str>),
+    OversizedValue(&'a OversizedValue)
+}
+
+impl<'a> From<&'a InFlightRecord> for CloudEvent<'a> {
+    fn from(record: &'a InFlightRecord) -> Self {
+        let time = record.timestamp.to_millis()
+            .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+            .map(|time| time.to_rfc3339());
+
+        let (datacontenttype, data) = match (&record.value, &record.oversized_value) {
+            (_, Some(oversized_value)) =>
+                (Some(OVERSIZED_VALUE_CONTENT_TYPE), Some(CloudEventData::OversizedValue(oversized_value))),
+            (Some(value), None) => match serde_json::from_slice::<&RawValue>(value) {
+                Ok(json) => (Some("application/json"), Some(CloudEventData::Json(json))),
+                Err(_) => (Some("text/plain"), Some(CloudEventData::Text(String::from_utf8_lossy(value))))
+            },
+            (None, None) => (None, None)
+        };
+
+        CloudEvent {
+            specversion: CLOUD_EVENTS_SPEC_VERSION,
+            id: format!("{}-{}-{}", &record.topic, record.partition, record.offset),
+            source: format!("/topics/{}/partitions/{}", &record.topic, record.partition),
+            event_type: CLOUD_EVENTS_TYPE,
+            time,
+            partitionkey: record.key_as_str(),
+            datacontenttype, data
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rdkafka::message::Timestamp;
+
+    use crate::conf::PayloadFormat;
+    use crate::kafka::consumer::InFlightRecord;
+
+    use super::PayloadEncoder;
+
+    fn record(partition: i32, offset: i64, value: &str) -> InFlightRecord {
+        InFlightRecord {
+            topic: "user.delete".to_string(),
+            partition, offset,
+            timestamp: Timestamp::CreateTime(1545084650987),
+            headers: vec!(("source".to_string(), "crm".as_bytes().to_vec())),
+            ..InFlightRecord::create(Some("k".as_bytes()), Some(value.as_bytes()))
+        }
+    }
+
+    fn measure(encoder: &PayloadEncoder, records: &[InFlightRecord]) -> usize {
+        let mut measured_size = encoder.empty_payload_size();
+        for (records_in_payload, record) in records.iter().enumerate() {
+            measured_size += encoder.payload_size_increment(record, records_in_payload);
+        }
+        measured_size
+    }
+
+    #[test]
+    fn should_encode_records_as_self_managed_kafka_event() {
+        let encoder = PayloadEncoder::create(PayloadFormat::SelfManagedKafka, "localhost:9092".to_string());
+        let records = [record(0, 15, "Hello"), record(1, 3, "World")];
+
+        let payload = String::from_utf8(encoder.encode(&records)).unwrap();
+        let expected = concat!(
+            r#"{"eventSource":"SelfManagedKafka","bootstrapServers":"localhost:9092","records":{"#,
+            r#""user.delete-0":[{"topic":"user.delete","partition":0,"offset":15,"timestamp":1545084650987,"timestampType":"CREATE_TIME","key":"aw==","value":"SGVsbG8=","headers":[{"source":[99,114,109]}]}],"#,
+            r#""user.delete-1":[{"topic":"user.delete","partition":1,"offset":3,"timestamp":1545084650987,"timestampType":"CREATE_TIME","key":"aw==","value":"V29ybGQ=","headers":[{"source":[99,114,109]}]}]}}"#
+        );
+        assert_eq!(expected, payload);
+        assert!(payload.len() <= measure(&encoder, &records));
+    }
+
+    #[test]
+    fn should_encode_records_as_cloud_events_batch() {
+        let encoder = PayloadEncoder::create(PayloadFormat::CloudEvents, "localhost:9092".to_string());
+        let records = [record(0, 15, r#"{"id":1}"#), record(0, 16, "plain")];
+
+        let payload = String::from_utf8(encoder.encode(&records)).unwrap();
+        let expected = concat!(
+            r#"[{"specversion":"1.0","id":"user.delete-0-15","source":"/topics/user.delete/partitions/0","type":"malka.kafka.record","#,
+            r#""time":"2018-12-17T22:10:50.987+00:00","partitionkey":"k","datacontenttype":"application/json","data":{"id":1}},"#,
+            r#"{"specversion":"1.0","id":"user.delete-0-16","source":"/topics/user.delete/partitions/0","type":"malka.kafka.record","#,
+            r#""time":"2018-12-17T22:10:50.987+00:00","partitionkey":"k","datacontenttype":"text/plain","data":"plain"}]"#
+        );
+        assert_eq!(expected, payload);
+        assert_eq!(payload.len(), measure(&encoder, &records));
+    }
+
+    #[test]
+    fn should_send_single_records_on_their_own_when_unwrapping() {
+        let encoder = PayloadEncoder::create(PayloadFormat::Array, "localhost:9092".to_string())
+            .unwrapping_single_records();
+
+        let payload = String::from_utf8(encoder.encode(&[record(0, 15, "Hello")])).unwrap();
+        assert_eq!(r#"{"key":"k","value":"Hello"}"#, payload);
+    }
+}
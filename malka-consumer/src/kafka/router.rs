@@ -0,0 +1,89 @@
+use crate::conf::{RouteConfig, TargetFunction};
+use crate::error::{KnownHandledErrors, Result};
+use crate::kafka::consumer::InFlightRecord;
+use crate::kafka::filter::{value_of, RecordMatcher};
+
+pub const METRIC_UNROUTED_RECORDS: &str = "unrouted_records";
+
+/// Picks the target function each record is delivered to, based on the subscription
+/// `routes`, so a single topic can feed many functions. Records take the first route
+/// they match. Those matching none are committed without being delivered.
+///
+/// Routed records are batched separately per target function by the `FanOutListener`.
+pub struct RecordRouter {
+    targets: Vec<String>,
+    matchers: Vec<RecordMatcher>
+}
+
+impl RecordRouter {
+
+    pub fn create(routes: &[RouteConfig], target_functions: &[TargetFunction]) -> Result<Self> {
+        let mut targets = Vec::with_capacity(routes.len());
+        let mut matchers = Vec::with_capacity(routes.len());
+        for route in routes {
+            if !target_functions.iter().any(|target_function| target_function.name == route.target) {
+                return Err(KnownHandledErrors::InvalidConfiguration(format!(
+                    "route to {} doesn't match any target function", &route.target)))
+            }
+            targets.push(route.target.clone());
+            matchers.push(RecordMatcher::create(&route.conditions)?);
+        }
+        Ok(RecordRouter { targets, matchers })
+    }
+
+    /// Sets the route of `record`. Returns `false` if it matches none of the routes.
+    pub fn route(&self, record: &mut InFlightRecord) -> bool {
+        if self.matchers.is_empty() {
+            return true
+        }
+
+        let value = value_of(record, &self.matchers);
+        let route = self.matchers.iter()
+            .position(|matcher| matcher.matches(record, value.as_ref()))
+            .map(|index| self.targets[index].clone());
+        let is_routed = route.is_some();
+        record.route = route;
+        is_routed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::conf::{RouteConfig, TargetFunction};
+    use crate::kafka::consumer::InFlightRecord;
+
+    use super::RecordRouter;
+
+    fn target_functions() -> Vec<TargetFunction> {
+        vec!(TargetFunction::from("user_deleted".to_string()), TargetFunction::from("user_audit".to_string()))
+    }
+
+    fn routed(router: &RecordRouter, key: &str, value: &str) -> Option<String> {
+        let mut record = InFlightRecord::create(Some(key.as_bytes()), Some(value.as_bytes()));
+        router.route(&mut record);
+        record.route
+    }
+
+    #[test]
+    fn should_route_records_to_the_first_route_they_match() {
+        let routes: Vec<RouteConfig> = serde_json::from_str(r#"[
+            { "target": "user_deleted", "value": { "$.eventType": ["DELETED"] } },
+            { "target": "user_audit", "key_pattern": "^admin-" }
+        ]"#).unwrap();
+        let router = RecordRouter::create(&routes, &target_functions()).unwrap();
+
+        assert_eq!(Some("user_deleted".to_string()), routed(&router, "admin-1", r#"{"eventType":"DELETED"}"#));
+        assert_eq!(Some("user_audit".to_string()), routed(&router, "admin-1", r#"{"eventType":"CREATED"}"#));
+        assert_eq!(None, routed(&router, "user-1", r#"{"eventType":"CREATED"}"#));
+
+        let mut unrouted = InFlightRecord::create(Some("user-1".as_bytes()), None);
+        assert!(!router.route(&mut unrouted));
+        assert!(RecordRouter::create(&[], &target_functions()).unwrap().route(&mut unrouted));
+    }
+
+    #[test]
+    fn should_reject_routes_to_unknown_target_functions() {
+        let routes: Vec<RouteConfig> = serde_json::from_str(r#"[{ "target": "user_created" }]"#).unwrap();
+        assert!(RecordRouter::create(&routes, &target_functions()).is_err());
+    }
+}
@@ -2,11 +2,16 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Acquire;
 
-use log::{error, trace};
+use log::{error, trace, warn};
 
 use KafkaConsumerResult::{Failed, Succeeded, NoMessagesConsumed};
 
+use crate::error::{KnownHandledErrors, Result};
 use crate::kafka::consumer::{KafkaConsumer, KafkaConsumerListener, KafkaConsumerResult, KafkaConsumerTransaction};
+use crate::kafka::error::{FailureHandling, KafkaConsumerError};
+use crate::metrics;
+
+pub const METRIC_CONSUMER_FAILURES: &str = "consumer_failures";
 
 /// A simplified Kafka subscriber. It wraps away the complexity of
 /// dealing with transactions when consuming messages.
@@ -24,34 +29,85 @@ impl<CONSUMER, LISTENER> KafkaSubscriber<CONSUMER, LISTENER>
           LISTENER: KafkaConsumerListener + std::marker::Sync {
 
     /// Performs the message consumption loop.
-    /// The loop will be interrupted once `should_poll_next_messages` is set to `false`.
-    pub async fn main_loop(&self) {
+    /// The loop will be interrupted once `should_poll_next_messages` is set to `false`,
+    /// or as soon as the transaction can't be committed or rolled back.
+    pub async fn main_loop(&self) -> Result<()> {
         while self.should_poll_next_messages.load(Acquire) {
             let result = self.consumer.consume(&self.listener).await;
             match result {
-                Failed(cause) => self.rollback(cause).await,
-                Succeeded => self.commit().await,
+                Failed(cause) => self.handle_failure(cause).await?,
+                Succeeded => self.commit().await?,
                 NoMessagesConsumed => {}
             }
         }
+        Ok(())
+    }
+
+    /// Picks the most appropriate way to handle a given failure.
+    async fn handle_failure(&self, cause: KafkaConsumerError) -> Result<()> {
+        let retriable = if cause.is_retriable() { "true" } else { "false" };
+        metrics::global().increment(METRIC_CONSUMER_FAILURES, &[
+            ("error", cause.label()), ("retriable", retriable)
+        ]);
+        match cause.handling() {
+            FailureHandling::Rollback => self.rollback(cause).await,
+            FailureHandling::Backoff => self.pause(cause).await,
+            FailureHandling::DeadLetter => self.dead_letter(cause).await,
+            FailureHandling::Halt => Err(self.halt(cause))
+        }
     }
 
     /// Commits the transaction and moves the cursor forward once
-    /// the message was correctly ingested.
-    async fn commit(&self) {
-        self.consumer.commit().await;
+    /// the message was correctly ingested. Listeners that don't commit
+    /// offsets have the transaction discarded instead.
+    async fn commit(&self) -> Result<()> {
+        if !self.listener.commits_offsets() {
+            self.consumer.discard().await?;
+            trace!("Dry run: most recent offset has been discarded.");
+            return Ok(())
+        }
+        self.consumer.commit().await?;
         trace!("Most recent offset has been committed.");
+        Ok(())
     }
 
-    /// Performs a rollback in the last execution in case of failure.
-    /// It's expected, though, only failures related to network communication
-    /// to be handled here. The message will be considered delivered even if
-    /// the Lambda function failed to handle the event. Dead-Letter Queues,
-    /// Backoff and Retries are capabilities provided out-of-box from AWS Lambda,
-    /// therefore doesn't need to be implemented in this application.
-    async fn rollback(&self, cause: String) {
+    /// Rolls back the transaction after a transient failure (`FailureHandling::Rollback`),
+    /// such as failing to poll messages or to reach the listener. Nothing is committed and
+    /// the cursor is moved back to the last committed offsets, so the very same records
+    /// will be consumed again right away. Throttling, rejected records and failures
+    /// retrying won't fix are handled by `pause`, `dead_letter` and `halt` instead.
+    async fn rollback(&self, cause: KafkaConsumerError) -> Result<()> {
         error!("Rolling back transaction. Failed to consume message: {}.", cause);
-        self.consumer.rollback().await;
+        self.consumer.rollback().await
+    }
+
+    /// Backs off while the listener is being throttled. The affected partitions
+    /// are paused for a while and the transaction is rolled back, so the
+    /// messages will be consumed again once the partitions are resumed.
+    async fn pause(&self, cause: KafkaConsumerError) -> Result<()> {
+        warn!("Pausing consumption. Listener is being throttled: {}.", cause);
+        self.consumer.pause().await?;
+        self.consumer.rollback().await
+    }
+
+    /// Moves the records that will never be accepted by the listener to the
+    /// dead-letter topic, so the consumption can move on. Halts if they can't be moved.
+    async fn dead_letter(&self, cause: KafkaConsumerError) -> Result<()> {
+        error!("Sending records to dead-letter topic. Listener rejected them: {}.", cause);
+        match self.consumer.dead_letter(cause.cause().records()).await {
+            Ok(_) => self.commit().await,
+            Err(failure) => {
+                error!("Could not send records to dead-letter topic: {}.", failure);
+                Err(self.halt(cause))
+            }
+        }
+    }
+
+    /// Stops consuming messages, as retrying won't fix the failure.
+    /// Records are kept uncommitted, so they will be consumed once the problem is fixed.
+    fn halt(&self, cause: KafkaConsumerError) -> KnownHandledErrors {
+        error!("Halting subscriber. Failed to consume message: {:?}.", cause);
+        KnownHandledErrors::SubscriberHalted(cause)
     }
 }
 
@@ -64,6 +120,9 @@ mod kafka_subscriber_tests {
         use std::sync::atomic::{AtomicBool, Ordering::*};
         use std::time::Duration;
 
+        use crate::error::KnownHandledErrors;
+        use crate::kafka::consumer::KafkaConsumerResult;
+        use crate::kafka::error::{FailureCause, KafkaConsumerError};
         use crate::kafka::consumer::mocks::MockKafkaConsumerAndTransaction as MockKafkaConsumer;
         use crate::kafka::consumer::mocks::MockKafkaConsumerListener;
         use crate::kafka::subscriber::KafkaSubscriber;
@@ -82,7 +141,7 @@ mod kafka_subscriber_tests {
             };
 
             let future = tokio::spawn(async move {
-                subscriber.main_loop().await;
+                subscriber.main_loop().await.expect("Subscriber failed");
             });
 
             tokio::time::sleep(Duration::from_millis(500)).await;
@@ -91,5 +150,105 @@ mod kafka_subscriber_tests {
             future.await.expect("Failed to shutdown thread");
             assert!(consumer_called.load(Relaxed));
         }
+
+        #[tokio::test]
+        async fn should_pause_and_rollback_when_listener_is_throttled() {
+            let should_poll_messages = Arc::new(AtomicBool::new(true));
+
+            let listener = MockKafkaConsumerListener::new();
+            let cause = FailureCause::new("Rate exceeded".to_string());
+            let consumer = MockKafkaConsumer::returning(KafkaConsumerResult::Failed(KafkaConsumerError::Throttled(cause)));
+            let pause_called = consumer.reference_to_check_if_pause_has_been_called();
+            let rollback_called = consumer.reference_to_check_if_rollback_has_been_called();
+
+            let subscriber = KafkaSubscriber {
+                should_poll_next_messages: Arc::clone(&should_poll_messages),
+                consumer, listener
+            };
+
+            let future = tokio::spawn(async move {
+                subscriber.main_loop().await.expect("Subscriber failed");
+            });
+
+            tokio::time::sleep(Duration::from_millis(700)).await;
+            should_poll_messages.store(false, Relaxed);
+
+            future.await.expect("Failed to shutdown thread");
+            assert!(pause_called.load(Relaxed));
+            assert!(rollback_called.load(Relaxed));
+        }
+
+        #[tokio::test]
+        async fn should_dead_letter_and_commit_records_rejected_by_the_listener() {
+            let should_poll_messages = Arc::new(AtomicBool::new(true));
+
+            let listener = MockKafkaConsumerListener::new();
+            let cause = FailureCause::new("Request too large".to_string());
+            let consumer = MockKafkaConsumer::returning(KafkaConsumerResult::Failed(KafkaConsumerError::PayloadTooLarge(cause)));
+            let dead_letter_called = consumer.reference_to_check_if_dead_letter_has_been_called();
+            let commit_called = consumer.reference_to_check_if_commit_has_been_called();
+
+            let subscriber = KafkaSubscriber {
+                should_poll_next_messages: Arc::clone(&should_poll_messages),
+                consumer, listener
+            };
+
+            let future = tokio::spawn(async move {
+                subscriber.main_loop().await.expect("Subscriber failed");
+            });
+
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            should_poll_messages.store(false, Relaxed);
+
+            future.await.expect("Failed to shutdown thread");
+            assert!(dead_letter_called.load(Relaxed));
+            assert!(commit_called.load(Relaxed));
+        }
+
+        #[tokio::test]
+        async fn should_discard_instead_of_committing_when_listener_only_validates_records() {
+            let should_poll_messages = Arc::new(AtomicBool::new(true));
+
+            let listener = MockKafkaConsumerListener::validating();
+            let consumer = MockKafkaConsumer::new();
+            let discard_called = consumer.reference_to_check_if_discard_has_been_called();
+            let commit_called = consumer.reference_to_check_if_commit_has_been_called();
+
+            let subscriber = KafkaSubscriber {
+                should_poll_next_messages: Arc::clone(&should_poll_messages),
+                consumer, listener
+            };
+
+            let future = tokio::spawn(async move {
+                subscriber.main_loop().await.expect("Subscriber failed");
+            });
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            should_poll_messages.store(false, Relaxed);
+
+            future.await.expect("Failed to shutdown thread");
+            assert!(discard_called.load(Relaxed));
+            assert!(!commit_called.load(Relaxed));
+        }
+
+        #[tokio::test]
+        async fn should_halt_when_failure_cannot_be_fixed_by_retrying() {
+            let should_poll_messages = Arc::new(AtomicBool::new(true));
+
+            let listener = MockKafkaConsumerListener::new();
+            let cause = FailureCause::new("Function not found".to_string());
+            let consumer = MockKafkaConsumer::returning(KafkaConsumerResult::Failed(KafkaConsumerError::NotFound(cause)));
+            let rollback_called = consumer.reference_to_check_if_rollback_has_been_called();
+
+            let subscriber = KafkaSubscriber {
+                should_poll_next_messages: Arc::clone(&should_poll_messages),
+                consumer, listener
+            };
+
+            let result = tokio::time::timeout(Duration::from_secs(1), subscriber.main_loop()).await
+                .expect("Subscriber should have halted");
+            assert!(matches!(result, Err(KnownHandledErrors::SubscriberHalted(KafkaConsumerError::NotFound(_)))));
+            assert!(!rollback_called.load(Relaxed));
+        }
     }
 }
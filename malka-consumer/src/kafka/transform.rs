@@ -0,0 +1,258 @@
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use wasmi::{Config, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::conf::TargetFunction;
+use crate::error::{KnownHandledErrors, Result};
+use crate::kafka::consumer::InFlightRecord;
+use crate::kafka::error::FailureCause;
+
+/// Runs every record through a WebAssembly module before it's delivered, so records can be
+/// dropped, reshaped or routed to a single target without invoking any of them.
+///
+/// Modules export their `memory`, an `alloc(len: i32) -> i32` function, and a
+/// `transform(ptr: i32, len: i32) -> i64` function. The latter receives a record as JSON
+/// (`{"topic", "partition", "offset", "key", "value", "headers": [[name, value]]}`) and
+/// returns where its decision was written to, as `ptr << 32 | len`. Returning `0` keeps
+/// the record as is. Otherwise, the decision is a JSON object either with `"drop": true`,
+/// or with the `key`, `value`, `headers` and `route` (a target name) to be replaced.
+///
+/// Dropped records are committed along with the rest of their batch. Records routed to an
+/// unknown target, or running out of fuel, fail the batch. Modules run on a blocking thread.
+#[derive(Clone)]
+pub struct WasmRecordTransformer {
+    path: Arc<String>,
+    targets: Arc<Vec<String>>,
+    instance: Arc<Mutex<WasmInstance>>
+}
+
+struct WasmInstance {
+    store: Store<()>,
+    fuel: u64,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>
+}
+
+#[derive(Serialize)]
+struct WasmRecord<'a> {
+    topic: &'a str,
+    partition: i32,
+    offset: i64,
+    key: Option<Cow<'a, str>>,
+    value: Option<Cow<'a, str>>,
+    headers: Vec<(&'a str, String)>
+}
+
+/// What should be done with a record.
+#[derive(Deserialize, Default)]
+struct WasmDecision {
+    #[serde(default)]
+    drop: bool,
+    key: Option<String>,
+    value: Option<String>,
+    headers: Option<Vec<(String, String)>>,
+    route: Option<String>
+}
+
+impl WasmRecordTransformer {
+
+    pub fn create(path: &str, fuel: u64, target_functions: &[TargetFunction]) -> Result<Self> {
+        let invalid_module = |cause: wasmi::Error| KnownHandledErrors::InvalidConfiguration(
+            format!("invalid WebAssembly module {}: {}", path, cause));
+
+        let engine = Engine::new(Config::default().consume_fuel(true));
+        let module = Module::new(&engine, std::fs::read(path)?.as_slice()).map_err(invalid_module)?;
+        let mut store = Store::new(&engine, ());
+        store.add_fuel(fuel).map_err(|cause| invalid_module(cause.into()))?;
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(invalid_module)?;
+
+        let memory = instance.get_memory(&store, "memory")
+            .ok_or_else(|| KnownHandledErrors::InvalidConfiguration(
+                format!("WebAssembly module {} doesn't export its memory", path)))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc").map_err(invalid_module)?;
+        let transform = instance.get_typed_func::<(i32, i32), i64>(&store, "transform").map_err(invalid_module)?;
+
+        Ok(WasmRecordTransformer {
+            path: Arc::new(path.to_string()),
+            targets: Arc::new(target_functions.iter().map(|target_function| target_function.name.clone()).collect()),
+            instance: Arc::new(Mutex::new(WasmInstance { store, fuel, memory, alloc, transform }))
+        })
+    }
+
+    /// Runs every record through the module, leaving the dropped ones out.
+    pub async fn transform(&self, records: &[InFlightRecord]) -> std::result::Result<Vec<InFlightRecord>, FailureCause> {
+        let transformer = self.clone();
+        let records = records.to_vec();
+        tokio::task::spawn_blocking(move || transformer.transform_blocking(&records))
+            .await
+            .map_err(|cause| FailureCause::with_source(format!("WebAssembly module {} didn't complete", &self.path), cause))?
+    }
+
+    fn transform_blocking(&self, records: &[InFlightRecord]) -> std::result::Result<Vec<InFlightRecord>, FailureCause> {
+        let mut instance = self.instance.lock().unwrap();
+        let mut transformed = Vec::with_capacity(records.len());
+        for record in records {
+            let decision = instance.decide(record)
+                .and_then(|decision| self.check_route_of(decision))
+                .map_err(|cause| FailureCause::new(format!(
+                    "WebAssembly module {} failed to transform record {}-{}@{}: {}",
+                    &self.path, &record.topic, record.partition, record.offset, cause)))?;
+            if let Some(record) = apply(decision, record) {
+                transformed.push(record);
+            }
+        }
+        Ok(transformed)
+    }
+
+    /// Records routed to unknown targets would never be delivered.
+    fn check_route_of(&self, decision: WasmDecision) -> std::result::Result<WasmDecision, String> {
+        match &decision.route {
+            Some(route) if !self.targets.contains(route) => Err(format!("unknown route {}", route)),
+            _ => Ok(decision)
+        }
+    }
+}
+
+impl WasmInstance {
+
+    fn decide(&mut self, record: &InFlightRecord) -> std::result::Result<WasmDecision, String> {
+        self.refuel()?;
+
+        let input = serde_json::to_vec(&WasmRecord {
+            topic: &record.topic,
+            partition: record.partition,
+            offset: record.offset,
+            key: record.key_as_str(),
+            value: record.value_as_str(),
+            headers: record.headers.iter()
+                .map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value).into_owned()))
+                .collect()
+        }).expect("Failed to serialize record");
+
+        let input_ptr = self.alloc.call(&mut self.store, input.len() as i32).map_err(|cause| cause.to_string())?;
+        self.memory.write(&mut self.store, input_ptr as usize, &input).map_err(|cause| cause.to_string())?;
+        let output = self.transform.call(&mut self.store, (input_ptr, input.len() as i32))
+            .map_err(|cause| cause.to_string())?;
+        if output == 0 {
+            return Ok(WasmDecision::default())
+        }
+
+        let (output_ptr, output_len) = ((output >> 32) as u32 as usize, output as u32 as usize);
+        let mut decision = vec![0; output_len];
+        self.memory.read(&self.store, output_ptr, &mut decision).map_err(|cause| cause.to_string())?;
+        serde_json::from_slice(&decision).map_err(|cause| format!("invalid decision: {}", cause))
+    }
+
+    /// Tops the store up, so every record gets the whole fuel budget.
+    fn refuel(&mut self) -> std::result::Result<(), String> {
+        let remaining = self.store.consume_fuel(0).map_err(|cause| cause.to_string())?;
+        self.store.add_fuel(self.fuel.saturating_sub(remaining)).map_err(|cause| cause.to_string())
+    }
+}
+
+fn apply(decision: WasmDecision, record: &InFlightRecord) -> Option<InFlightRecord> {
+    if decision.drop {
+        return None
+    }
+
+    let mut record = record.clone();
+    if let Some(key) = decision.key {
+        record.key = Some(key.into_bytes());
+    }
+    if let Some(value) = decision.value {
+        record.value = Some(value.into_bytes());
+    }
+    if let Some(headers) = decision.headers {
+        record.headers = headers.into_iter().map(|(name, value)| (name, value.into_bytes())).collect();
+    }
+    if decision.route.is_some() {
+        record.route = decision.route;
+    }
+    Some(record)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::conf::TargetFunction;
+    use crate::kafka::consumer::InFlightRecord;
+
+    use super::WasmRecordTransformer;
+
+    const FUEL: u64 = 10_000;
+
+    /// A module, written to `file_name`, that decides the same for every record:
+    /// `decision`, if any, or to keep it as is.
+    fn module_deciding(file_name: &str, decision: Option<&str>) -> String {
+        let output = match decision {
+            Some(decision) => format!("(i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const {}))", decision.len()),
+            None => "(i64.const 0)".to_string()
+        };
+        module_transforming(file_name, decision.unwrap_or_default(), &output)
+    }
+
+    /// A module, written to `file_name`, whose `transform` function runs `body`, with `data` in its memory.
+    fn module_transforming(file_name: &str, data: &str, body: &str) -> String {
+        let wat = format!(r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 16) "{}")
+            (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "transform") (param i32 i32) (result i64) {}))"#,
+            data.replace('"', "\\\""), body);
+
+        let path: PathBuf = std::env::temp_dir().join(file_name);
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn transformer_of(module: &str) -> WasmRecordTransformer {
+        let target_functions = vec!(TargetFunction::from("user_deleted".to_string()), TargetFunction::from("user_audit".to_string()));
+        WasmRecordTransformer::create(module, FUEL, &target_functions).unwrap()
+    }
+
+    fn records() -> Vec<InFlightRecord> {
+        vec!(InFlightRecord::create(Some("k".as_bytes()), Some("v".as_bytes())))
+    }
+
+    #[tokio::test]
+    async fn should_reshape_and_route_records() {
+        let decision = r#"{"value":"enriched","headers":[["source","wasm"]],"route":"user_audit"}"#;
+        let module = module_deciding("malka-reshaping-transform.wasm", Some(decision));
+        let transformer = transformer_of(&module);
+
+        let transformed = transformer.transform(&records()).await.unwrap();
+        assert_eq!(Some(b"k".to_vec()), transformed[0].key);
+        assert_eq!(Some(b"enriched".to_vec()), transformed[0].value);
+        assert_eq!(vec!(("source".to_string(), b"wasm".to_vec())), transformed[0].headers);
+        assert_eq!(Some("user_audit".to_string()), transformed[0].route);
+    }
+
+    #[tokio::test]
+    async fn should_drop_or_keep_records() {
+        let module = module_deciding("malka-dropping-transform.wasm", Some(r#"{"drop":true}"#));
+        let transformer = transformer_of(&module);
+        assert!(transformer.transform(&records()).await.unwrap().is_empty());
+
+        let module = module_deciding("malka-keeping-transform.wasm", None);
+        let transformer = transformer_of(&module);
+        assert_eq!(Some(b"v".to_vec()), transformer.transform(&records()).await.unwrap()[0].value);
+    }
+
+    #[tokio::test]
+    async fn should_fail_records_running_out_of_fuel_or_routed_to_unknown_targets() {
+        let module = module_transforming("malka-looping-transform.wasm", "", "(loop $forever (br $forever)) (i64.const 0)");
+        let transformer = transformer_of(&module);
+        assert!(transformer.transform(&records()).await.is_err());
+
+        let module = module_deciding("malka-misrouting-transform.wasm", Some(r#"{"route":"user_created"}"#));
+        let transformer = transformer_of(&module);
+        assert!(transformer.transform(&records()).await.is_err());
+    }
+}
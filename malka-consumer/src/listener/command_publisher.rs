@@ -0,0 +1,258 @@
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::conf::CommandConfig;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::PayloadEncoder;
+
+/// Exit code (`EX_DATAERR`) telling the records can't be handled, and should be dead-lettered.
+const EXIT_CODE_INVALID_PAYLOAD: i32 = 65;
+/// Exit code (`EX_TEMPFAIL`) telling the command is overloaded, and records should be retried later.
+const EXIT_CODE_THROTTLED: i32 = 75;
+
+/// A `KafkaConsumerListener` implementation that runs a local command per batch,
+/// writing the payload to its stdin. Records are considered consumed when the
+/// command exits with `0`. Any other exit code is retried, except for
+/// `65` (dead-lettered) and `75` (backed off).
+///
+/// Commands may also print which records failed to stdout, as in
+/// `{"failures": [{"partition": 0, "offset": 12, "reason": "invalid_payload"}]}`,
+/// in which case the batch fails with the prevailing reason. Only the reported
+/// records are dead-lettered, and records that aren't part of the batch are ignored.
+pub struct CommandKafkaConsumerListener {
+    config: CommandConfig,
+    payload_encoder: PayloadEncoder
+}
+
+/// What commands might print to stdout, describing records they failed to handle.
+#[derive(Deserialize)]
+struct CommandOutput {
+    #[serde(default)]
+    failures: Vec<RecordFailure>
+}
+
+#[derive(Deserialize)]
+struct RecordFailure {
+    partition: i32,
+    offset: i64,
+    reason: FailureReason
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum FailureReason {
+    Retry,
+    Throttled,
+    InvalidPayload
+}
+
+impl CommandKafkaConsumerListener {
+    pub fn create(config: CommandConfig, payload_encoder: PayloadEncoder) -> Self {
+        CommandKafkaConsumerListener { config, payload_encoder }
+    }
+
+    /// Runs the command, returning its exit status and stdout.
+    async fn run(&self, payload: Vec<u8>) -> std::io::Result<(ExitStatus, Vec<u8>)> {
+        let mut command = Command::new(&self.config.command);
+        command.args(&self.config.args)
+            .envs(&self.config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+        if let Some(working_dir) = &self.config.working_dir {
+            command.current_dir(working_dir);
+        }
+
+        let mut child = command.spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let write_payload = async move {
+            match stdin.write_all(&payload).await {
+                // Commands are free to ignore their input.
+                Err(cause) if cause.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+                result => result
+            }
+        };
+
+        let (written, output) = tokio::join!(write_payload, child.wait_with_output());
+        let output = output?;
+        written?;
+        Ok((output.status, output.stdout))
+    }
+
+    fn classify_output(&self, records: &[InFlightRecord], status: ExitStatus, stdout: &[u8]) -> KafkaConsumerResult {
+        let failures = self.failures_reported_in(records, stdout);
+        if status.success() && failures.is_empty() {
+            return KafkaConsumerResult::Succeeded
+        }
+
+        if !failures.is_empty() {
+            return KafkaConsumerResult::Failed(KafkaConsumerError::prevailing(failures).unwrap())
+        }
+
+        let cause = FailureCause::with_source(
+            format!("Command {} failed", &self.config.command), UnsuccessfulExit(status));
+        let failure = match status.code() {
+            Some(EXIT_CODE_INVALID_PAYLOAD) => KafkaConsumerError::InvalidPayload(cause),
+            Some(EXIT_CODE_THROTTLED) => KafkaConsumerError::Throttled(cause),
+            _ => KafkaConsumerError::Network(cause)
+        };
+        KafkaConsumerResult::Failed(failure)
+    }
+
+    fn failures_reported_in(&self, records: &[InFlightRecord], stdout: &[u8]) -> Vec<KafkaConsumerError> {
+        let output: CommandOutput = match serde_json::from_slice(stdout) {
+            Ok(output) => output,
+            Err(cause) => {
+                if !stdout.is_empty() {
+                    debug!("Ignoring output of command {}: {}", &self.config.command, cause);
+                }
+                return Vec::new()
+            }
+        };
+
+        output.failures.into_iter()
+            .filter_map(|failure| {
+                let record = records.iter()
+                    .find(|record| record.partition == failure.partition && record.offset == failure.offset);
+                let record = match record {
+                    Some(record) => record,
+                    None => {
+                        warn!("Command {} reported a failure of record {}@{}, which isn't part of the batch. Ignoring it.",
+                              &self.config.command, failure.partition, failure.offset);
+                        return None
+                    }
+                };
+
+                let cause = FailureCause::new(format!(
+                    "Command {} failed to handle record {}-{}@{}",
+                    &self.config.command, &record.topic, record.partition, record.offset));
+                let failure = match failure.reason {
+                    FailureReason::Retry => KafkaConsumerError::Network(cause),
+                    FailureReason::Throttled => KafkaConsumerError::Throttled(cause),
+                    FailureReason::InvalidPayload => KafkaConsumerError::InvalidPayload(cause)
+                };
+                Some(failure.affecting(vec!(record.coordinates())))
+            })
+            .collect()
+    }
+}
+
+/// A command exit status that couldn't be handled as success.
+#[derive(Debug)]
+struct UnsuccessfulExit(ExitStatus);
+
+impl std::fmt::Display for UnsuccessfulExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command exited with {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsuccessfulExit {}
+
+#[async_trait]
+impl KafkaConsumerListener for CommandKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let timeout = Duration::from_millis(self.config.timeout);
+        let payload = self.payload_encoder.encode(records);
+
+        match tokio::time::timeout(timeout, self.run(payload)).await {
+            Ok(Ok((status, stdout))) => self.classify_output(records, status, &stdout),
+            Ok(Err(cause)) => {
+                warn!("Failed to run command {}: {}", &self.config.command, cause);
+                let message = format!("Failed to run command {}", &self.config.command);
+                let failure = match cause.kind() {
+                    std::io::ErrorKind::NotFound => KafkaConsumerError::NotFound,
+                    std::io::ErrorKind::PermissionDenied => KafkaConsumerError::PermissionDenied,
+                    _ => KafkaConsumerError::Network
+                };
+                KafkaConsumerResult::Failed(failure(FailureCause::with_source(message, cause)))
+            },
+            Err(cause) => KafkaConsumerResult::Failed(KafkaConsumerError::Network(FailureCause::with_source(
+                format!("Command {} timed out after {:?}", &self.config.command, timeout), cause)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::conf::{CommandConfig, PayloadFormat};
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+    use crate::kafka::error::KafkaConsumerError;
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::CommandKafkaConsumerListener;
+
+    fn listener(script: &str) -> CommandKafkaConsumerListener {
+        let config = CommandConfig {
+            command: "sh".to_string(),
+            args: vec!("-c".to_string(), script.to_string()),
+            env: HashMap::from([("EXPECTED".to_string(), r#"[{"key":"k","value":"v"}]"#.to_string())]),
+            working_dir: None,
+            timeout: 1000
+        };
+        CommandKafkaConsumerListener::create(config, PayloadEncoder::create(PayloadFormat::Array, String::new()))
+    }
+
+    fn records() -> Vec<InFlightRecord> {
+        vec!(InFlightRecord::create(Some("k".as_bytes()), Some("v".as_bytes())))
+    }
+
+    #[tokio::test]
+    async fn should_write_records_to_the_command_stdin() {
+        let result = listener(r#"test "$(cat)" = "$EXPECTED""#).consume(&records()).await;
+        assert_eq!(KafkaConsumerResult::Succeeded, result);
+
+        let result = listener("exit 1").consume(&records()).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Network(_))));
+
+        let result = listener("exit 65").consume(&records()).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::InvalidPayload(_))));
+
+        let result = listener("sleep 5").consume(&records()).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn should_fail_with_the_prevailing_reason_reported_by_the_command() {
+        let script = r#"echo '{"failures": [
+            {"partition": 0, "offset": 1, "reason": "invalid_payload"},
+            {"partition": 0, "offset": 2, "reason": "throttled"}
+        ]}'"#;
+
+        let records: Vec<InFlightRecord> = (0..3)
+            .map(|offset| InFlightRecord { offset, ..InFlightRecord::create(None, None) })
+            .collect();
+        let result = listener(script).consume(&records).await;
+        assert!(matches!(result, KafkaConsumerResult::Failed(KafkaConsumerError::Throttled(_))));
+    }
+
+    #[tokio::test]
+    async fn should_only_fail_the_reported_records_of_the_batch() {
+        let script = r#"echo '{"failures": [
+            {"partition": 0, "offset": 0, "reason": "invalid_payload"},
+            {"partition": 0, "offset": 7, "reason": "throttled"}
+        ]}'"#;
+
+        let result = listener(script).consume(&records()).await;
+        let failure = match result {
+            KafkaConsumerResult::Failed(failure @ KafkaConsumerError::InvalidPayload(_)) => failure,
+            result => panic!("Unexpected result: {:?}", result)
+        };
+        assert_eq!(Some(&[(String::new(), 0, 0)][..]), failure.cause().records());
+
+        let result = listener(r#"echo '{"failures": [{"partition": 0, "offset": 7, "reason": "retry"}]}'"#)
+            .consume(&records()).await;
+        assert_eq!(KafkaConsumerResult::Succeeded, result);
+    }
+}
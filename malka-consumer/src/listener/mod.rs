@@ -0,0 +1,3 @@
+pub mod command_publisher;
+pub mod registry;
+pub mod stdout_publisher;
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rdkafka::ClientConfig;
+use serde::de::DeserializeOwned;
+
+use crate::aws::batch_publisher::{BatchDestination, BatchKafkaConsumerListener};
+use crate::aws::client::{HttpPoolConfig, LambdaClientRegistry};
+use crate::aws::client_context::ConsumerIdentity;
+use crate::aws::lambda_publisher::AwsLambdaKafkaConsumerListener;
+use crate::aws::step_functions_publisher::StepFunctionsKafkaConsumerListener;
+use crate::conf::{CommandConfig, GrpcConfig, KafkaForwardConfig, SnsConfig, SqsConfig, StepFunctionsConfig, TargetFunction, WebhookConfig};
+use crate::error::{KnownHandledErrors, Result};
+use crate::grpc::grpc_publisher::GrpcKafkaConsumerListener;
+use crate::http::webhook_publisher::WebhookKafkaConsumerListener;
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::forward_publisher::ForwardingKafkaConsumerListener;
+use crate::kafka::payload::PayloadEncoder;
+use crate::listener::command_publisher::CommandKafkaConsumerListener;
+use crate::listener::stdout_publisher::StdoutKafkaConsumerListener;
+
+/// A listener of any target `type`.
+pub type BoxedListener = Box<dyn KafkaConsumerListener + Send + Sync>;
+
+/// Creates the listener of a target function.
+pub type ListenerFactory = dyn Fn(ListenerContext) -> Result<BoxedListener> + Send + Sync;
+
+/// Checks the settings of a target function, so misconfigured targets are rejected on subscription.
+pub type SettingsValidator = dyn Fn(&TargetFunction) -> Result<()> + Send + Sync;
+
+/// Everything a `ListenerFactory` might need to create a listener.
+pub struct ListenerContext {
+    pub target_function: TargetFunction,
+    pub consumer: ConsumerIdentity,
+    pub payload_encoder: PayloadEncoder,
+    /// Settings of producers created on behalf of the subscription.
+    pub producer_config: ClientConfig
+}
+
+#[async_trait]
+impl KafkaConsumerListener for BoxedListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        self.as_ref().consume(records).await
+    }
+
+    fn max_payload_size(&self) -> Option<usize> {
+        self.as_ref().max_payload_size()
+    }
+
+    fn max_record_size(&self) -> Option<usize> {
+        self.as_ref().max_record_size()
+    }
+
+    fn commits_offsets(&self) -> bool {
+        self.as_ref().commits_offsets()
+    }
+
+    fn empty_payload_size(&self, encoder: &PayloadEncoder) -> usize {
+        self.as_ref().empty_payload_size(encoder)
+    }
+
+    fn payload_size_increment(&self, encoder: &PayloadEncoder, record: &InFlightRecord, records_in_payload: usize) -> usize {
+        self.as_ref().payload_size_increment(encoder, record, records_in_payload)
+    }
+}
+
+/// Maps target `type`s to the factories of their listeners, so new kinds of
+/// targets can be plugged in by registering them.
+#[derive(Clone, Default)]
+pub struct ListenerRegistry {
+    registrations: HashMap<String, Registration>
+}
+
+#[derive(Clone)]
+struct Registration {
+    validator: Arc<SettingsValidator>,
+    factory: Arc<ListenerFactory>
+}
+
+impl ListenerRegistry {
+
+    /// Creates a registry with all the target types Malka ships with.
+    pub fn create() -> Result<Self> {
+        let lambda_clients = LambdaClientRegistry::create(HttpPoolConfig::from_env()?)?;
+
+        let mut registry = ListenerRegistry::default();
+        let aws_clients = lambda_clients.clone();
+        registry.register_with_settings::<SqsConfig, _>("sqs", move |context| {
+            let config: SqsConfig = context.target_function.settings_as()?;
+            let (client, region) = aws_clients.service_client_for(&context.target_function)?;
+            Ok(Box::new(BatchKafkaConsumerListener::create(
+                BatchDestination::Sqs(config), context.payload_encoder, client, region)))
+        });
+        let aws_clients = lambda_clients.clone();
+        registry.register_with_settings::<SnsConfig, _>("sns", move |context| {
+            let config: SnsConfig = context.target_function.settings_as()?;
+            let (client, region) = aws_clients.service_client_for(&context.target_function)?;
+            Ok(Box::new(BatchKafkaConsumerListener::create(
+                BatchDestination::Sns(config), context.payload_encoder, client, region)))
+        });
+        let aws_clients = lambda_clients.clone();
+        registry.register_with_settings::<StepFunctionsConfig, _>("step_functions", move |context| {
+            let config: StepFunctionsConfig = context.target_function.settings_as()?;
+            let (client, region) = aws_clients.service_client_for(&context.target_function)?;
+            Ok(Box::new(StepFunctionsKafkaConsumerListener::create(config, context.payload_encoder, client, region)))
+        });
+        registry.register("lambda", move |context| {
+            let lambda_client = lambda_clients.client_for(&context.target_function)?;
+            Ok(Box::new(AwsLambdaKafkaConsumerListener::create(
+                context.target_function, context.consumer, context.payload_encoder, lambda_client)))
+        });
+        registry.register_with_settings::<WebhookConfig, _>("webhook", |context| {
+            let config: WebhookConfig = context.target_function.settings_as()?;
+            Ok(Box::new(WebhookKafkaConsumerListener::create(config, context.payload_encoder)))
+        });
+        registry.register_with_settings::<GrpcConfig, _>("grpc", |context| {
+            let config: GrpcConfig = context.target_function.settings_as()?;
+            Ok(Box::new(GrpcKafkaConsumerListener::create(config, context.consumer)?))
+        });
+        registry.register_with_settings::<KafkaForwardConfig, _>("kafka", |context| {
+            let config: KafkaForwardConfig = context.target_function.settings_as()?;
+            Ok(Box::new(ForwardingKafkaConsumerListener::create(config, context.producer_config)?))
+        });
+        registry.register_with_settings::<CommandConfig, _>("command", |context| {
+            let config: CommandConfig = context.target_function.settings_as()?;
+            Ok(Box::new(CommandKafkaConsumerListener::create(config, context.payload_encoder)))
+        });
+        registry.register("stdout", |context| {
+            Ok(Box::new(StdoutKafkaConsumerListener::create(context.payload_encoder)))
+        });
+        Ok(registry)
+    }
+
+    /// Registers the factory of `target_type` listeners, replacing any previously registered one.
+    /// Targets of this type are expected to have no settings of their own.
+    pub fn register<F>(&mut self, target_type: &str, factory: F)
+        where F: Fn(ListenerContext) -> Result<BoxedListener> + Send + Sync + 'static
+    {
+        self.register_validated(target_type, without_settings, factory)
+    }
+
+    /// Registers the factory of `target_type` listeners, whose targets have `S` settings.
+    pub fn register_with_settings<S, F>(&mut self, target_type: &str, factory: F)
+        where S: DeserializeOwned, F: Fn(ListenerContext) -> Result<BoxedListener> + Send + Sync + 'static
+    {
+        self.register_validated(target_type, |target_function| target_function.settings_as::<S>().map(|_| ()), factory)
+    }
+
+    fn register_validated<V, F>(&mut self, target_type: &str, validator: V, factory: F)
+        where V: Fn(&TargetFunction) -> Result<()> + Send + Sync + 'static,
+              F: Fn(ListenerContext) -> Result<BoxedListener> + Send + Sync + 'static
+    {
+        let registration = Registration { validator: Arc::new(validator), factory: Arc::new(factory) };
+        self.registrations.insert(target_type.to_string(), registration);
+    }
+
+    /// Checks that the type of `target_function` is known, and that its settings are valid for it.
+    pub fn validate(&self, target_function: &TargetFunction) -> Result<()> {
+        (self.registration_of(target_function)?.validator)(target_function)
+    }
+
+    /// Creates the listener of the target function found in `context`.
+    pub fn create_listener(&self, context: ListenerContext) -> Result<BoxedListener> {
+        let factory = Arc::clone(&self.registration_of(&context.target_function)?.factory);
+        factory(context)
+    }
+
+    fn registration_of(&self, target_function: &TargetFunction) -> Result<&Registration> {
+        self.registrations.get(&target_function.target_type).ok_or_else(|| KnownHandledErrors::InvalidConfiguration(format!(
+            "unknown type '{}' of target {}", &target_function.target_type, &target_function.name)))
+    }
+}
+
+fn without_settings(target_function: &TargetFunction) -> Result<()> {
+    match target_function.settings.keys().next() {
+        None => Ok(()),
+        Some(setting) => Err(KnownHandledErrors::InvalidConfiguration(format!(
+            "unknown setting '{}' for {} target {}", setting, &target_function.target_type, &target_function.name)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+    use rdkafka::ClientConfig;
+
+    use crate::aws::client_context::ConsumerIdentity;
+    use crate::conf::{PayloadFormat, TargetFunction, WebhookConfig};
+    use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+    use crate::kafka::payload::PayloadEncoder;
+
+    use super::{ListenerContext, ListenerRegistry};
+
+    struct FixedPayloadSizeListener(usize);
+
+    #[async_trait]
+    impl KafkaConsumerListener for FixedPayloadSizeListener {
+        async fn consume(&self, _records: &[InFlightRecord]) -> KafkaConsumerResult {
+            KafkaConsumerResult::Succeeded
+        }
+
+        fn max_payload_size(&self) -> Option<usize> {
+            Some(self.0)
+        }
+    }
+
+    fn context(target_type: &str) -> ListenerContext {
+        ListenerContext {
+            target_function: TargetFunction {
+                name: "user_deleted".to_string(),
+                target_type: target_type.to_string(),
+                ..TargetFunction::default()
+            },
+            consumer: ConsumerIdentity { group_id: "group".to_string(), group_instance_id: "group-0".to_string() },
+            payload_encoder: PayloadEncoder::create(PayloadFormat::Array, String::new()),
+            producer_config: ClientConfig::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn should_create_listeners_of_registered_types() {
+        let mut registry = ListenerRegistry::default();
+        registry.register("fixed", |_| Ok(Box::new(FixedPayloadSizeListener(1024))));
+
+        let listener = registry.create_listener(context("fixed")).unwrap();
+        assert_eq!(Some(1024), listener.max_payload_size());
+        assert_eq!(KafkaConsumerResult::Succeeded, listener.consume(&[InFlightRecord::create(None, None)]).await);
+
+        assert!(registry.create_listener(context("carrier_pigeon")).is_err());
+    }
+
+    #[test]
+    fn should_validate_the_settings_of_each_target_type() {
+        let mut registry = ListenerRegistry::default();
+        registry.register("fixed", |_| Ok(Box::new(FixedPayloadSizeListener(1024))));
+        registry.register_with_settings::<WebhookConfig, _>("webhook", |_| Ok(Box::new(FixedPayloadSizeListener(1024))));
+
+        let webhook: TargetFunction = serde_json::from_str(r#"{"name": "users", "type": "webhook", "url": "http://users"}"#).unwrap();
+        assert!(registry.validate(&webhook).is_ok());
+
+        let misspelled: TargetFunction = serde_json::from_str(r#"{"name": "users", "type": "webhook", "url": "http://users", "timout": 10}"#).unwrap();
+        assert!(registry.validate(&misspelled).is_err());
+        let missing_url: TargetFunction = serde_json::from_str(r#"{"name": "users", "type": "webhook"}"#).unwrap();
+        assert!(registry.validate(&missing_url).is_err());
+
+        assert!(registry.validate(&context("fixed").target_function).is_ok());
+        let with_settings: TargetFunction = serde_json::from_str(r#"{"name": "users", "type": "fixed", "qualifer": "v1"}"#).unwrap();
+        assert!(registry.validate(&with_settings).is_err());
+        assert!(registry.validate(&context("carrier_pigeon").target_function).is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+
+use crate::kafka::consumer::{InFlightRecord, KafkaConsumerListener, KafkaConsumerResult};
+use crate::kafka::error::{FailureCause, KafkaConsumerError};
+use crate::kafka::payload::PayloadEncoder;
+
+/// A `KafkaConsumerListener` implementation that prints every payload, one per line,
+/// to the standard output. Handy to inspect a topic, or to try out a subscription.
+pub struct StdoutKafkaConsumerListener {
+    payload_encoder: PayloadEncoder
+}
+
+impl StdoutKafkaConsumerListener {
+    pub fn create(payload_encoder: PayloadEncoder) -> Self {
+        StdoutKafkaConsumerListener { payload_encoder }
+    }
+}
+
+#[async_trait]
+impl KafkaConsumerListener for StdoutKafkaConsumerListener {
+
+    async fn consume(&self, records: &[InFlightRecord]) -> KafkaConsumerResult {
+        let mut payload = self.payload_encoder.encode(records);
+        payload.push(b'\n');
+
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        match stdout.write_all(&payload).and_then(|_| stdout.flush()) {
+            Ok(_) => KafkaConsumerResult::Succeeded,
+            Err(cause) => KafkaConsumerResult::Failed(KafkaConsumerError::Network(
+                FailureCause::with_source("Failed to write records to stdout".to_string(), cause)))
+        }
+    }
+}
@@ -2,11 +2,18 @@ use crate::conf::SubscriptionConfig;
 use crate::manager::SubscriptionManager;
 use std::{env, fs};
 use std::env::Args;
+use log::error;
 
+mod backoff;
 mod error;
 mod kafka;
 mod aws;
 mod conf;
+mod grpc;
+mod health;
+mod http;
+mod listener;
+mod metrics;
 pub mod manager;
 
 #[tokio::main]
@@ -22,7 +29,17 @@ async fn main() -> error::Result<()> {
 async fn run_consumer(args: Args) -> error::Result<()> {
     env_logger::init();
 
-    let mut manager = SubscriptionManager::default();
+    let mut manager = SubscriptionManager::create()?;
+
+    if let Ok(address) = env::var("HEALTH_CHECK_ADDRESS") {
+        let address = address.parse()?;
+        let health = manager.health();
+        tokio::spawn(async move {
+            if let Err(cause) = health::serve(address, health).await {
+                error!("Could not serve health reports: {}", cause);
+            }
+        });
+    }
 
     args.skip(1)
         .map(|file_name| fs::read_to_string(file_name).unwrap())
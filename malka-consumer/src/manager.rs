@@ -1,74 +1,163 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::process;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 
-use log::{info, trace};
+use log::{error, info, trace};
 
-use crate::aws::lambda_publisher::AwsLambdaKafkaConsumerListener;
-use crate::conf::SubscriptionConfig;
+use crate::aws::client_context::ConsumerIdentity;
+use crate::backoff::Backoff;
+use crate::conf::{CommitPolicy, ConsumerGroupMode, DispatchMode, RestartPolicy, SHARED_CONSUMER_GROUP_NAME, SubscriptionConfig, TargetFunction};
+use crate::health::{HealthRegistry, SubscriberHealth};
+use crate::kafka::dead_letter::DeadLetterPublisher;
 use crate::kafka::defaults::DefaultKafkaConsumer;
+use crate::kafka::dispatcher::RecordDispatcher;
+use crate::kafka::fan_out::FanOutListener;
+use crate::kafka::oversized::OversizedRecordHandler;
+use crate::kafka::payload::PayloadEncoder;
 use crate::kafka::subscriber::KafkaSubscriber;
+use crate::listener::registry::{BoxedListener, ListenerContext, ListenerRegistry};
 use crate::error::Result;
-use std::sync::atomic::Ordering::Release;
+use std::sync::atomic::Ordering::{Acquire, Release};
 use tokio::task::JoinHandle;
 
-type DefaultKafkaSubscriber = KafkaSubscriber<DefaultKafkaConsumer, AwsLambdaKafkaConsumerListener>;
+type DefaultKafkaSubscriber = KafkaSubscriber<DefaultKafkaConsumer, FanOutListener<RecordDispatcher<BoxedListener>>>;
 type SubscriberEnabledFlag = Arc<AtomicBool>;
 type SubscribersRef = HashMap<String, SubscriberEnabledFlag>;
 
+/// Exit code used when a subscriber exceeds its restart budget.
+const EXIT_CODE_RESTART_BUDGET_EXCEEDED: i32 = 70;
+
 pub struct SubscriptionManager {
     subscribers: SubscribersRef,
-    subscribers_thread_future: Vec<JoinHandle<()>>
-}
-
-impl Default for SubscriptionManager {
-    fn default() -> Self {
-        SubscriptionManager {
-            subscribers: HashMap::new(),
-            subscribers_thread_future: Vec::new()
-        }
-    }
+    subscribers_thread_future: Vec<JoinHandle<()>>,
+    health: HealthRegistry,
+    listeners: ListenerRegistry
 }
 
 impl SubscriptionManager {
 
+    pub fn create() -> Result<Self> {
+        Ok(SubscriptionManager {
+            subscribers: SubscribersRef::new(),
+            subscribers_thread_future: Vec::new(),
+            health: HealthRegistry::default(),
+            listeners: ListenerRegistry::create()?
+        })
+    }
+
     /// Subscribe to a give `topic subscription configuration`.
     pub fn subscribe(&mut self, subscription: SubscriptionConfig) -> Result<()> {
+        subscription.validate()?;
         for target_function in subscription.target_functions.iter() {
-            for parallel_consumer_id in 0..subscription.topic_number_of_consumers {
-                self.subscribe_to_function(&subscription, target_function, parallel_consumer_id)?;
+            self.listeners.validate(target_function)?;
+        }
+        match subscription.consumer_group_mode {
+            ConsumerGroupMode::PerFunction => {
+                for target_function in subscription.target_functions.iter() {
+                    let target_subscription = subscription.overridden_by(target_function);
+                    let targets = ConsumerGroupTargets {
+                        name: target_function.id(),
+                        target_functions: vec!(target_function.clone()),
+                        commit_policy: CommitPolicy::AllSucceeded
+                    };
+                    self.subscribe_to_functions(&target_subscription, &targets)?;
+                }
+            },
+            ConsumerGroupMode::Shared { commit_policy } => {
+                let targets = ConsumerGroupTargets {
+                    name: SHARED_CONSUMER_GROUP_NAME.to_string(),
+                    target_functions: subscription.target_functions.clone(),
+                    commit_policy
+                };
+                self.subscribe_to_functions(&subscription, &targets)?;
             }
         }
 
         Ok(())
     }
 
-    fn subscribe_to_function(&mut self, subscription: &SubscriptionConfig, target_function: &str, parallel_consumer_id: u32) -> Result<()> {
-        let subscriber = SubscriptionManager::create_subscriber_from(
-            subscription, target_function, parallel_consumer_id)?;
-        let flag = Arc::clone(&subscriber.should_poll_next_messages);
+    /// The health of every subscriber managed by this instance.
+    pub fn health(&self) -> HealthRegistry {
+        self.health.clone()
+    }
+
+    fn subscribe_to_functions(&mut self, subscription: &SubscriptionConfig, targets: &ConsumerGroupTargets) -> Result<()> {
+        for parallel_consumer_id in 0..subscription.topic_number_of_consumers {
+            self.subscribe_consumer_to_functions(subscription, targets, parallel_consumer_id)?;
+        }
+        Ok(())
+    }
+
+    fn subscribe_consumer_to_functions(
+        &mut self, subscription: &SubscriptionConfig, targets: &ConsumerGroupTargets, parallel_consumer_id: u32
+    ) -> Result<()> {
+        let config = subscription.as_client_config_for(&targets.name, parallel_consumer_id)?;
+        let group_instance_id = config.get("group.instance.id").unwrap().to_string();
+        let flag = Arc::new(AtomicBool::new(true));
+
+        let supervised_subscriber = SupervisedSubscriber {
+            subscription: subscription.clone(),
+            targets: targets.clone(),
+            parallel_consumer_id,
+            group_instance_id: group_instance_id.clone(),
+            should_poll_next_messages: Arc::clone(&flag),
+            health: self.health.clone(),
+            listeners: self.listeners.clone()
+        };
 
         let future = tokio::spawn(async move {
-            subscriber.main_loop().await
+            supervised_subscriber.supervise().await
         });
 
-        self.subscribers.insert(subscription.topic_name.clone(), flag);
+        self.subscribers.insert(group_instance_id, flag);
         self.subscribers_thread_future.push(future);
 
         Ok(())
     }
 
-    fn create_subscriber_from(subscription: &SubscriptionConfig, target_function: &str, parallel_consumer_id: u32) -> Result<DefaultKafkaSubscriber>
+    fn create_subscriber_from(
+        subscription: &SubscriptionConfig, targets: &ConsumerGroupTargets, parallel_consumer_id: u32,
+        should_poll_next_messages: SubscriberEnabledFlag, listener_registry: &ListenerRegistry
+    ) -> Result<DefaultKafkaSubscriber>
     {
-        let config = subscription.as_client_config_for(target_function, parallel_consumer_id);
+        let config = subscription.as_client_config_for(&targets.name, parallel_consumer_id)?;
         let group_instance_id = config.get("group.instance.id").unwrap();
-        let listener = AwsLambdaKafkaConsumerListener::create(target_function.to_string());
-        let should_poll_next_messages = Arc::new(AtomicBool::new(true));
+        let consumer_identity = ConsumerIdentity {
+            group_id: config.get("group.id").unwrap().to_string(),
+            group_instance_id: group_instance_id.to_string()
+        };
+        let bootstrap_servers = config.get("bootstrap.servers").unwrap_or_default();
+
+        let mut listeners = Vec::with_capacity(targets.target_functions.len());
+        for target_function in &targets.target_functions {
+            let dispatch_mode = subscription.overridden_by(target_function).dispatch_mode;
+            let mut payload_encoder = PayloadEncoder::create(subscription.payload_format, bootstrap_servers.to_string());
+            if let DispatchMode::PerRecord { unwrap_payload: true, .. } = dispatch_mode {
+                payload_encoder = payload_encoder.unwrapping_single_records();
+            }
+            let target_listener = listener_registry.create_listener(ListenerContext {
+                target_function: target_function.clone(),
+                consumer: consumer_identity.clone(),
+                payload_encoder,
+                producer_config: subscription.as_producer_config()
+            })?;
+            listeners.push((target_function.name.clone(), RecordDispatcher::create(dispatch_mode, target_listener)));
+        }
+        let listener = FanOutListener::create(targets.commit_policy, listeners);
+        let oversized_record_handler = OversizedRecordHandler::create(
+            &subscription.oversized_record_policy,
+            subscription.as_producer_config())?;
+        let dead_letter = match &subscription.dead_letter_topic {
+            Some(topic) => Some(DeadLetterPublisher::create(topic.clone(), subscription.as_producer_config())?),
+            None => None
+        };
         let consumer = DefaultKafkaConsumer::create(
             group_instance_id.to_string(),
-            subscription.topic_name.to_string(),
-            subscription.topic_max_buffer_size,
-            subscription.topic_max_buffer_await_time,
+            subscription,
+            oversized_record_handler,
+            dead_letter,
             config)?;
 
         Ok(KafkaSubscriber {
@@ -90,10 +179,157 @@ impl Drop for SubscriptionManager {
     fn drop(&mut self) {
         let subscribers = &mut self.subscribers;
 
-        for (topic_name, flag) in subscribers.iter_mut() {
-            info!("Unsubscribing to topic {}", &topic_name);
+        for (group_instance_id, flag) in subscribers.iter_mut() {
+            info!("Unsubscribing {}", &group_instance_id);
             flag.store(false, Release);
-            trace!("Successfully unsubscribed to topic {}", topic_name)
+            trace!("Successfully unsubscribed {}", group_instance_id)
+        }
+    }
+}
+
+/// The target functions fed by the very same consumer group.
+#[derive(Clone)]
+struct ConsumerGroupTargets {
+    /// Identifies the consumer group within its subscription.
+    name: String,
+    target_functions: Vec<TargetFunction>,
+    commit_policy: CommitPolicy
+}
+
+/// Everything needed to (re)create a subscriber whenever it fails.
+struct SupervisedSubscriber {
+    subscription: SubscriptionConfig,
+    targets: ConsumerGroupTargets,
+    parallel_consumer_id: u32,
+    group_instance_id: String,
+    should_poll_next_messages: SubscriberEnabledFlag,
+    health: HealthRegistry,
+    listeners: ListenerRegistry
+}
+
+impl SupervisedSubscriber {
+
+    /// Runs the subscriber until it's unsubscribed. Whenever it fails or panics,
+    /// the failure is reported and a brand new subscriber (and consumer) is
+    /// created after a backoff time. The process exits once the subscriber
+    /// fails more often than its `RestartPolicy` allows.
+    async fn supervise(self) {
+        let policy = &self.subscription.restart_policy;
+        let max_backoff_time = Duration::from_millis(policy.max_backoff_time);
+        let mut restart_backoff = Backoff::new(Duration::from_millis(policy.backoff_time), max_backoff_time);
+        let mut restart_budget = RestartBudget::new(policy);
+        let mut failures = 0;
+
+        while self.should_poll_next_messages.load(Acquire) {
+            let started_at = Instant::now();
+            let result = self.run_subscriber().await;
+
+            match result {
+                Ok(_) => break,
+                Err(cause) => {
+                    failures += 1;
+                    if !restart_budget.try_restart(Instant::now()) {
+                        error!("[{}] Subscriber exceeded its budget of {} restart(s) within {:?}. Giving up: {}.",
+                               &self.group_instance_id, restart_budget.max_restarts, restart_budget.window, cause);
+                        self.health.update(&self.group_instance_id, SubscriberHealth::Stopped);
+                        process::exit(EXIT_CODE_RESTART_BUDGET_EXCEEDED);
+                    }
+
+                    if started_at.elapsed() >= max_backoff_time {
+                        restart_backoff.reset();
+                    }
+
+                    let delay = restart_backoff.next_delay();
+                    error!("[{}] Subscriber failed: {}. Restarting in {:?}.", &self.group_instance_id, cause, delay);
+                    self.health.update(&self.group_instance_id, SubscriberHealth::Recovering {
+                        failures, last_failure: cause
+                    });
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+
+        self.health.update(&self.group_instance_id, SubscriberHealth::Stopped);
+    }
+
+    /// Creates and runs a subscriber in its own task, so panics can be told apart
+    /// from a regular termination.
+    async fn run_subscriber(&self) -> std::result::Result<(), String> {
+        let subscriber = self.create_subscriber()
+            .map_err(|cause| cause.to_string())?;
+        self.health.update(&self.group_instance_id, SubscriberHealth::Running);
+
+        let task = tokio::spawn(async move {
+            subscriber.main_loop().await
+        });
+
+        match task.await {
+            Ok(result) => result.map_err(|cause| cause.to_string()),
+            Err(cause) if cause.is_panic() => Err("Subscriber panicked".to_string()),
+            Err(cause) => Err(cause.to_string())
+        }
+    }
+
+    fn create_subscriber(&self) -> Result<DefaultKafkaSubscriber> {
+        SubscriptionManager::create_subscriber_from(
+            &self.subscription, &self.targets, self.parallel_consumer_id,
+            Arc::clone(&self.should_poll_next_messages), &self.listeners)
+    }
+}
+
+/// Keeps track of how many times a subscriber was restarted within the `RestartPolicy` window.
+struct RestartBudget {
+    max_restarts: u32,
+    window: Duration,
+    restarts: VecDeque<Instant>
+}
+
+impl RestartBudget {
+    fn new(policy: &RestartPolicy) -> Self {
+        RestartBudget {
+            max_restarts: policy.max_restarts,
+            window: Duration::from_millis(policy.restart_window),
+            restarts: VecDeque::new()
+        }
+    }
+
+    /// Registers a restart happening at `now`. Returns `false` if
+    /// it would exceed the number of restarts allowed within the window.
+    fn try_restart(&mut self, now: Instant) -> bool {
+        while let Some(oldest) = self.restarts.front() {
+            if now.duration_since(*oldest) < self.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+
+        if self.restarts.len() >= self.max_restarts as usize {
+            return false
         }
+
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::conf::RestartPolicy;
+    use crate::manager::RestartBudget;
+
+    #[test]
+    fn should_only_allow_max_restarts_within_the_window() {
+        let policy = RestartPolicy { max_restarts: 2, restart_window: 1000, ..RestartPolicy::default() };
+        let mut budget = RestartBudget::new(&policy);
+        let start = Instant::now();
+
+        assert!(budget.try_restart(start));
+        assert!(budget.try_restart(start + Duration::from_millis(100)));
+        assert!(!budget.try_restart(start + Duration::from_millis(200)));
+
+        assert!(budget.try_restart(start + Duration::from_millis(1050)));
+        assert!(!budget.try_restart(start + Duration::from_millis(1060)));
     }
 }
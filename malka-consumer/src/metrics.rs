@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+
+use log::debug;
+
+/// A counter identifier: its name followed by its `(label, value)` pairs.
+type CounterKey = (String, Vec<(String, String)>);
+
+/// Process-wide counters, used to keep track of events that won't
+/// surface as errors (e.g. skipped records). They're served, in the
+/// Prometheus text format, along with the health reports.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<CounterKey, u64>>
+}
+
+/// Returns the process-wide `Metrics` instance.
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+
+    /// Increments the counter identified by `name` and `labels` by one.
+    pub fn increment(&self, name: &str, labels: &[(&str, &str)]) {
+        let key = Metrics::key_for(name, labels);
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(key).or_insert(0);
+        *counter += 1;
+        debug!("Metric {}{:?} = {}", name, labels, counter);
+    }
+
+    /// Retrieves the current value of the counter identified by `name` and `labels`.
+    #[cfg(test)]
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let key = Metrics::key_for(name, labels);
+        let counters = self.counters.lock().unwrap();
+        counters.get(&key).copied().unwrap_or(0)
+    }
+
+    /// Renders every counter in the Prometheus text format, named after its `malka_` prefixed name.
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut rendered = String::new();
+        let mut previous_name = None;
+        for ((name, labels), value) in counters.iter() {
+            if previous_name != Some(name) {
+                writeln!(rendered, "# TYPE malka_{} counter", name).unwrap();
+                previous_name = Some(name);
+            }
+            let labels: Vec<String> = labels.iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                .collect();
+            if labels.is_empty() {
+                writeln!(rendered, "malka_{} {}", name, value).unwrap();
+            } else {
+                writeln!(rendered, "malka_{}{{{}}} {}", name, labels.join(","), value).unwrap();
+            }
+        }
+        rendered
+    }
+
+    fn key_for(name: &str, labels: &[(&str, &str)]) -> CounterKey {
+        let labels = labels.iter()
+            .map(|(label, value)| (label.to_string(), value.to_string()))
+            .collect();
+        (name.to_string(), labels)
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+
+    #[test]
+    fn should_count_each_label_combination_independently() {
+        let metrics = Metrics::default();
+        metrics.increment("records", &[("topic", "user.delete")]);
+        metrics.increment("records", &[("topic", "user.delete")]);
+        metrics.increment("records", &[("topic", "user.update")]);
+
+        assert_eq!(2, metrics.counter("records", &[("topic", "user.delete")]));
+        assert_eq!(1, metrics.counter("records", &[("topic", "user.update")]));
+        assert_eq!(0, metrics.counter("records", &[]));
+    }
+
+    #[test]
+    fn should_render_counters_in_the_prometheus_text_format() {
+        let metrics = Metrics::default();
+        metrics.increment("records", &[("topic", "user.delete"), ("policy", "skip")]);
+        metrics.increment("records", &[("topic", "user.\"update\"")]);
+        metrics.increment("restarts", &[]);
+
+        let expected = "# TYPE malka_records counter\n\
+            malka_records{topic=\"user.\\\"update\\\"\"} 1\n\
+            malka_records{topic=\"user.delete\",policy=\"skip\"} 1\n\
+            # TYPE malka_restarts counter\n\
+            malka_restarts 1\n";
+        assert_eq!(expected, metrics.render());
+    }
+}